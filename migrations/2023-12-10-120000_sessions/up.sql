@@ -0,0 +1,10 @@
+CREATE TABLE sessions (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  user_id VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  last_seen TIMESTAMP NOT NULL DEFAULT NOW(),
+  refresh_token TEXT,
+  expires_at TIMESTAMP
+);
+
+CREATE INDEX sessions_user_id ON sessions (user_id);
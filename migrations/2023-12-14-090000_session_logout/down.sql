@@ -0,0 +1,2 @@
+ALTER TABLE sessions DROP COLUMN sid;
+ALTER TABLE sessions DROP COLUMN id_token;
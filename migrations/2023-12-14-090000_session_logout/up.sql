@@ -0,0 +1,4 @@
+ALTER TABLE sessions ADD COLUMN id_token TEXT;
+ALTER TABLE sessions ADD COLUMN sid VARCHAR;
+
+CREATE INDEX sessions_sid ON sessions (sid);
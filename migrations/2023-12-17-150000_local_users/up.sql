@@ -0,0 +1,5 @@
+CREATE TABLE local_users (
+  username VARCHAR NOT NULL PRIMARY KEY,
+  password_hash TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
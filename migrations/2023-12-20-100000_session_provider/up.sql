@@ -0,0 +1,5 @@
+ALTER TABLE sessions ADD COLUMN provider VARCHAR;
+
+-- User IDs are now namespaced by the OIDC server's issuer, so any existing
+-- sessions would refer to the wrong users
+DELETE FROM sessions WHERE id_token IS NOT NULL;
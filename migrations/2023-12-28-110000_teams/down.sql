@@ -0,0 +1,5 @@
+ALTER TABLE urls DROP COLUMN team_id;
+ALTER TABLE urls DROP COLUMN user_id;
+DROP TABLE team_prefixes;
+DROP TABLE team_members;
+DROP TABLE teams;
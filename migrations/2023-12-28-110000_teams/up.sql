@@ -0,0 +1,24 @@
+CREATE TABLE teams (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR NOT NULL UNIQUE,
+  oidc_group VARCHAR
+);
+
+CREATE TABLE team_members (
+  team_id INTEGER NOT NULL REFERENCES teams (id) ON DELETE CASCADE,
+  user_id VARCHAR NOT NULL,
+  is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+  synced BOOLEAN NOT NULL DEFAULT FALSE,
+  PRIMARY KEY(team_id, user_id)
+);
+
+CREATE INDEX team_members_user_id ON team_members (user_id);
+
+CREATE TABLE team_prefixes (
+  team_id INTEGER NOT NULL REFERENCES teams (id) ON DELETE CASCADE,
+  prefix TEXT NOT NULL,
+  PRIMARY KEY(team_id, prefix)
+);
+
+ALTER TABLE urls ADD COLUMN user_id VARCHAR;
+ALTER TABLE urls ADD COLUMN team_id INTEGER REFERENCES teams (id) ON DELETE SET NULL;
@@ -0,0 +1,7 @@
+ALTER TABLE team_prefixes DROP COLUMN max_length;
+ALTER TABLE team_prefixes DROP COLUMN deny;
+ALTER TABLE team_prefixes DROP COLUMN kind;
+
+ALTER TABLE prefixes DROP COLUMN max_length;
+ALTER TABLE prefixes DROP COLUMN deny;
+ALTER TABLE prefixes DROP COLUMN kind;
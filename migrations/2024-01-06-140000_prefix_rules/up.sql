@@ -0,0 +1,9 @@
+-- Prefixes can now also be glob patterns or regular expressions, deny names
+-- rather than allowing them, and limit the length of names
+ALTER TABLE prefixes ADD COLUMN kind VARCHAR NOT NULL DEFAULT 'prefix';
+ALTER TABLE prefixes ADD COLUMN deny BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE prefixes ADD COLUMN max_length INTEGER;
+
+ALTER TABLE team_prefixes ADD COLUMN kind VARCHAR NOT NULL DEFAULT 'prefix';
+ALTER TABLE team_prefixes ADD COLUMN deny BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE team_prefixes ADD COLUMN max_length INTEGER;
@@ -0,0 +1,2 @@
+DROP INDEX urls_user_id_created_at;
+ALTER TABLE urls DROP COLUMN created_at;
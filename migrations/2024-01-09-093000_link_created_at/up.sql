@@ -0,0 +1,6 @@
+-- Existing links are given an old timestamp so that they don't count towards
+-- anyone's quota for the current time window
+ALTER TABLE urls ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT '1970-01-01';
+ALTER TABLE urls ALTER COLUMN created_at SET DEFAULT now();
+
+CREATE INDEX urls_user_id_created_at ON urls (user_id, created_at);
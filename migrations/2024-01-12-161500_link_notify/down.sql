@@ -0,0 +1,2 @@
+DROP TRIGGER urls_notify_change ON urls;
+DROP FUNCTION notify_link_change();
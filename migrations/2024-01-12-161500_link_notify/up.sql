@@ -0,0 +1,22 @@
+-- Tells every instance when a link changes, so they can update their caches
+CREATE FUNCTION notify_link_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('link_changes', json_build_object('op', TG_OP, 'name', OLD.name)::text);
+        RETURN NULL;
+    END IF;
+
+    PERFORM pg_notify('link_changes', json_build_object('op', TG_OP, 'name', NEW.name)::text);
+
+    -- A renamed link no longer exists under its old name
+    IF TG_OP = 'UPDATE' AND NEW.name <> OLD.name THEN
+        PERFORM pg_notify('link_changes', json_build_object('op', 'DELETE', 'name', OLD.name)::text);
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER urls_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON urls
+    FOR EACH ROW EXECUTE FUNCTION notify_link_change();
@@ -0,0 +1,34 @@
+CREATE TABLE webhooks (
+    id SERIAL PRIMARY KEY,
+    -- The user who registered the webhook
+    user_id VARCHAR NOT NULL,
+    url TEXT NOT NULL,
+    -- Used to sign the deliveries so the receiver can check them
+    secret VARCHAR NOT NULL,
+    -- The events which are sent, all of them if empty
+    events TEXT[] NOT NULL DEFAULT '{}',
+    -- Whether events for every link are sent, rather than just the links the
+    -- user or their teams own. Only administrators can set this.
+    all_links BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+-- An outbox of events to send, which is kept as a log once they are sent
+CREATE TABLE webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event VARCHAR NOT NULL,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMP,
+    -- Set once we have given up retrying
+    failed BOOLEAN NOT NULL DEFAULT FALSE,
+    last_status INTEGER,
+    last_error TEXT
+);
+
+CREATE INDEX webhook_deliveries_pending ON webhook_deliveries (next_attempt_at)
+    WHERE delivered_at IS NULL AND NOT failed;
+CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id, created_at);
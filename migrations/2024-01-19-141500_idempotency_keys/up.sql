@@ -0,0 +1,13 @@
+-- The responses to requests made with an Idempotency-Key, so that retries
+-- get the same response instead of making the change again
+CREATE TABLE idempotency_keys (
+    user_id VARCHAR NOT NULL,
+    key VARCHAR NOT NULL,
+    -- A hash of the request, so the key can't be reused for a different one
+    request_hash VARCHAR NOT NULL,
+    response TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE INDEX idempotency_keys_created_at ON idempotency_keys (created_at);
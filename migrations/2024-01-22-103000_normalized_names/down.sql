@@ -0,0 +1,4 @@
+DROP INDEX urls_skeleton;
+DROP INDEX urls_normalized_name;
+ALTER TABLE urls DROP COLUMN skeleton;
+ALTER TABLE urls DROP COLUMN normalized_name;
@@ -0,0 +1,25 @@
+-- The form of each name links are looked up by, and its confusable skeleton.
+-- These are kept up to date with the configuration by the application, so
+-- this only fills them in with the names folded to lower case to begin with.
+ALTER TABLE urls ADD COLUMN normalized_name VARCHAR;
+ALTER TABLE urls ADD COLUMN skeleton VARCHAR;
+
+-- Where names are already the same once normalised, the link which is already
+-- in that form (or else the oldest) gets it, and the others keep their own
+-- name until they are renamed
+UPDATE urls
+SET normalized_name = CASE WHEN ranked.n = 1 THEN ranked.normalized ELSE urls.name END
+FROM (
+    SELECT name,
+           lower(name) AS normalized,
+           row_number() OVER (PARTITION BY lower(name) ORDER BY name = lower(name) DESC, created_at, name) AS n
+    FROM urls
+) AS ranked
+WHERE urls.name = ranked.name;
+UPDATE urls SET skeleton = normalized_name;
+
+ALTER TABLE urls ALTER COLUMN normalized_name SET NOT NULL;
+ALTER TABLE urls ALTER COLUMN skeleton SET NOT NULL;
+
+CREATE UNIQUE INDEX urls_normalized_name ON urls (normalized_name);
+CREATE INDEX urls_skeleton ON urls (skeleton);
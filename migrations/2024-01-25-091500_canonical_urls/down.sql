@@ -0,0 +1,2 @@
+DROP INDEX urls_canonical_url;
+ALTER TABLE urls DROP COLUMN canonical_url;
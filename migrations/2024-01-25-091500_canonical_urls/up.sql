@@ -0,0 +1,8 @@
+-- The URL each link redirects to in its canonical form, which is used to look
+-- for links to the same page. This is filled in by the application, as it
+-- depends on the configuration.
+ALTER TABLE urls ADD COLUMN canonical_url TEXT;
+UPDATE urls SET canonical_url = url;
+ALTER TABLE urls ALTER COLUMN canonical_url SET NOT NULL;
+
+CREATE INDEX urls_canonical_url ON urls (canonical_url);
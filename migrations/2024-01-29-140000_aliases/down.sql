@@ -0,0 +1,7 @@
+DROP TRIGGER urls_sync_aliases ON urls;
+DROP FUNCTION sync_aliases();
+
+DELETE FROM urls WHERE alias_of IS NOT NULL;
+DROP INDEX urls_alias_of;
+ALTER TABLE urls DROP CONSTRAINT urls_alias_of_other;
+ALTER TABLE urls DROP COLUMN alias_of;
@@ -0,0 +1,23 @@
+-- Aliases are extra names for a link. They are rows of their own, so names
+-- stay unique across links and aliases, but they follow the link they are an
+-- alias of and are removed along with it.
+ALTER TABLE urls ADD COLUMN alias_of VARCHAR REFERENCES urls (name) ON DELETE CASCADE ON UPDATE CASCADE;
+ALTER TABLE urls ADD CONSTRAINT urls_alias_of_other CHECK (alias_of <> name);
+
+CREATE INDEX urls_alias_of ON urls (alias_of);
+
+-- Changing a link changes all of its aliases, which also tells every instance
+-- to update their caches for them
+CREATE FUNCTION sync_aliases() RETURNS trigger AS $$
+BEGIN
+    UPDATE urls
+    SET url = NEW.url, canonical_url = NEW.canonical_url, team_id = NEW.team_id
+    WHERE alias_of = NEW.name;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER urls_sync_aliases
+    AFTER UPDATE OF url, canonical_url, team_id ON urls
+    FOR EACH ROW WHEN (NEW.alias_of IS NULL)
+    EXECUTE FUNCTION sync_aliases();
@@ -0,0 +1,9 @@
+DROP INDEX urls_search;
+DROP TRIGGER urls_update_search ON urls;
+DROP FUNCTION update_search();
+ALTER TABLE urls DROP COLUMN search;
+
+DROP INDEX urls_tags;
+ALTER TABLE urls DROP COLUMN tags;
+ALTER TABLE urls DROP COLUMN description;
+ALTER TABLE urls DROP COLUMN title;
@@ -0,0 +1,31 @@
+-- Titles, descriptions and tags which help people find links. Aliases don't
+-- have their own, they use the link's.
+ALTER TABLE urls ADD COLUMN title VARCHAR;
+ALTER TABLE urls ADD COLUMN description TEXT;
+ALTER TABLE urls ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX urls_tags ON urls USING GIN (tags);
+
+-- The words links are searched by. The 'simple' configuration is used as
+-- names and tags aren't words in any one language.
+ALTER TABLE urls ADD COLUMN search TSVECTOR NOT NULL DEFAULT ''::tsvector;
+
+CREATE FUNCTION update_search() RETURNS trigger AS $$
+BEGIN
+    NEW.search :=
+        setweight(to_tsvector('simple', NEW.name), 'A') ||
+        setweight(to_tsvector('simple', coalesce(NEW.title, '')), 'A') ||
+        setweight(to_tsvector('simple', array_to_string(NEW.tags, ' ')), 'A') ||
+        setweight(to_tsvector('simple', coalesce(NEW.description, '')), 'B') ||
+        setweight(to_tsvector('simple', NEW.url), 'C');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER urls_update_search
+    BEFORE INSERT OR UPDATE OF name, url, title, description, tags ON urls
+    FOR EACH ROW EXECUTE FUNCTION update_search();
+
+UPDATE urls SET title = title;
+
+CREATE INDEX urls_search ON urls USING GIN (search);
@@ -0,0 +1,2 @@
+-- Whether the link is shown in the directory of links
+ALTER TABLE urls ADD COLUMN listed BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,17 @@
+DROP TRIGGER urls_sync_aliases ON urls;
+CREATE TRIGGER urls_sync_aliases
+    AFTER UPDATE OF url, canonical_url, team_id ON urls
+    FOR EACH ROW WHEN (NEW.alias_of IS NULL)
+    EXECUTE FUNCTION sync_aliases();
+
+CREATE OR REPLACE FUNCTION sync_aliases() RETURNS trigger AS $$
+BEGIN
+    UPDATE urls
+    SET url = NEW.url, canonical_url = NEW.canonical_url, team_id = NEW.team_id
+    WHERE alias_of = NEW.name;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- The extension is left, as it could be used by something else
+DROP INDEX urls_normalized_name_trgm;
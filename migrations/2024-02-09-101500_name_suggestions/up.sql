@@ -0,0 +1,24 @@
+-- Trigram indexes find the names closest to one which doesn't exist
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE INDEX urls_normalized_name_trgm ON urls USING GIN (normalized_name gin_trgm_ops);
+
+-- Aliases are listed when their link is, so they can be suggested too
+CREATE OR REPLACE FUNCTION sync_aliases() RETURNS trigger AS $$
+BEGIN
+    UPDATE urls
+    SET url = NEW.url, canonical_url = NEW.canonical_url, team_id = NEW.team_id,
+        listed = NEW.listed
+    WHERE alias_of = NEW.name;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER urls_sync_aliases ON urls;
+CREATE TRIGGER urls_sync_aliases
+    AFTER UPDATE OF url, canonical_url, team_id, listed ON urls
+    FOR EACH ROW WHEN (NEW.alias_of IS NULL)
+    EXECUTE FUNCTION sync_aliases();
+
+UPDATE urls AS alias SET listed = link.listed
+FROM urls AS link
+WHERE alias.alias_of = link.name;
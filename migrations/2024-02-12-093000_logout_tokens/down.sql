@@ -0,0 +1 @@
+DROP TABLE logout_tokens;
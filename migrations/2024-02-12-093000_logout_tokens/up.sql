@@ -0,0 +1,11 @@
+-- The IDs of back-channel logout tokens which have been used, so that the
+-- same token can't be replayed. They are only kept until the token would be
+-- refused for being too old anyway.
+CREATE TABLE logout_tokens (
+    issuer VARCHAR NOT NULL,
+    jti VARCHAR NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (issuer, jti)
+);
+
+CREATE INDEX logout_tokens_expires_at ON logout_tokens (expires_at);
@@ -0,0 +1,8 @@
+-- The names users are shown by to other users, such as in the directory.
+-- These are taken from the authentication server when the user logs in, as
+-- user IDs from OIDC servers are only meant for the application.
+CREATE TABLE display_names (
+    user_id VARCHAR PRIMARY KEY,
+    display_name VARCHAR NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,8 @@
+-- How many times each name has been followed. These are kept apart from the
+-- links so that counting doesn't notify the caches that the links changed,
+-- and are counted per name so a link's total includes its aliases.
+CREATE TABLE link_hits (
+    name VARCHAR PRIMARY KEY REFERENCES urls (name) ON DELETE CASCADE,
+    hits BIGINT NOT NULL DEFAULT 0,
+    last_hit_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -2,19 +2,69 @@
 
 use diesel_async::AsyncConnection;
 use rocket::fairing::AdHoc;
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::request::Request;
 use rocket::response::Redirect;
+use rocket::{Build, Rocket, State};
 use rocket_db_pools::Connection;
 use rocket_dyn_templates::{context, Template};
 
-use crate::api::API_LOCAL;
-use crate::auth::{self, User};
-use crate::database::{Db, PrefixLink};
+use crate::api::{self, API_LOCAL};
+use crate::auth::{self, Admin, User};
+use crate::config::{AppConfig, AuthProvider};
+use crate::csrf;
+use crate::database::{Db, LinkHits, LinkOrder, LinkSearch, PrefixLink, Session, Team, Url};
+use crate::error::AppError;
+use crate::names;
+use crate::quotas;
+use crate::rules::{RuleCache, RuleKind};
 use crate::utils::random_colour;
 
+/// Where the admin interface is mounted, so that other stages can put their
+/// pages alongside it and redirect back to it
+pub struct AdminRoute(pub Origin<'static>);
+
+impl AdminRoute {
+    /// Returns where the admin interface is mounted. Stages adding pages to
+    /// it have to be attached after [`stage`].
+    pub fn get(rocket: &Rocket<Build>) -> Origin<'static> {
+        rocket
+            .state::<AdminRoute>()
+            .expect("The admin stage must be attached first")
+            .0
+            .clone()
+    }
+}
+
+/// Splits a name into the longest of the prefixes it starts with, and the
+/// rest of the name
+pub fn split_prefix<'a>(prefixes: &[&'a str], name: &'a str) -> (Option<&'a str>, &'a str) {
+    let prefix = prefixes
+        .iter()
+        .filter(|p| name.starts_with(**p))
+        .max_by_key(|p| p.len())
+        .copied();
+
+    match prefix {
+        Some(prefix) => (Some(prefix), &name[prefix.len()..]),
+        None => (None, name),
+    }
+}
+
 /// Once a user is logged in, show the admin panel with the prefixes which the
-/// user is allowed to use
-#[get("/")]
-pub async fn index(mut db: Connection<Db>, user: User) -> Template {
+/// user is allowed to use. The name of a new link can be filled in, such as
+/// from the page for a link which doesn't exist.
+#[get("/?<name>")]
+pub async fn index(
+    config: &State<AppConfig>,
+    patterns: &State<RuleCache>,
+    user: User,
+    mut db: Connection<Db>,
+    name: Option<&str>,
+) -> Template {
+    let user_id = user.id.clone();
+    let csrf_token = csrf::token(&user.session);
     let prefixes: Vec<PrefixLink> = db
         .transaction(|conn| {
             Box::pin(async move {
@@ -24,28 +74,177 @@ pub async fn index(mut db: Connection<Db>, user: User) -> Template {
         .await
         .unwrap_or_default();
 
+    let teams = Team::get_for_user(&mut db, &user_id).await;
+    let usage = quotas::usage(&mut db, patterns, &config.quotas, &user_id, &prefixes).await;
+
+    // Only plain prefixes can be picked from the list, names for the other
+    // rules are typed out in full
+    let patterns = prefixes
+        .iter()
+        .any(|p| !p.deny && p.kind != RuleKind::Prefix.as_str());
+    let allow_custom_name = prefixes.iter().any(|p| !p.deny);
+    let mut choices: Vec<&str> = prefixes
+        .iter()
+        .filter(|p| !p.deny && p.kind == RuleKind::Prefix.as_str())
+        .map(|p| p.prefix.as_str())
+        .collect();
+    choices.sort_unstable();
+    choices.dedup();
+    if patterns && !choices.contains(&"") {
+        choices.insert(0, "");
+    }
+
+    let (new_prefix, new_name) = split_prefix(&choices, name.unwrap_or_default());
+
     Template::render(
         "shortener",
         context! {
             api: API_LOCAL,
             colour: random_colour(),
-            allow_custom_name: !prefixes.is_empty(),
-            prefixes: prefixes,
+            allow_custom_name: allow_custom_name,
+            prefixes: choices,
+            teams: teams,
+            usage: usage,
+            is_admin: config.is_admin(&user_id),
+            local_users: config.auth_provider == AuthProvider::Local,
+            directory: config.directory,
+            csrf_token: csrf_token,
+            new_prefix: new_prefix,
+            new_name: new_name,
             name: "Home",
         },
     )
 }
 
+/// Shows a link and its aliases with how often each has been followed, along
+/// with the forms to change them if the user is allowed to. Any of the link's
+/// names can be given.
+#[get("/links?<name>")]
+pub async fn link(
+    config: &State<AppConfig>,
+    user: User,
+    mut db: Connection<Db>,
+    name: &str,
+) -> Result<Template, AppError> {
+    let not_found = || AppError::new(Status::NotFound, "This link does not exist");
+    let found = Url::get(&mut db, &names::normalize(&config.names, name))
+        .await
+        .ok_or_else(not_found)?;
+    let link = match &found.alias_of {
+        Some(target) => Url::get(&mut db, &names::normalize(&config.names, target))
+            .await
+            .ok_or_else(not_found)?,
+        None => found,
+    };
+
+    let can_edit = Url::user_can_edit(
+        &mut db,
+        &user.id,
+        config.is_admin(&user.id),
+        &link.normalized_name,
+    )
+    .await;
+    let aliases = Url::aliases(&mut db, &link.name).await;
+    let names: Vec<&str> = std::iter::once(link.name.as_str())
+        .chain(aliases.iter().map(|a| a.name.as_str()))
+        .collect();
+    let mut hits = LinkHits::get_all(&mut db, &names).await;
+    for name in names {
+        hits.entry(name.to_string()).or_default();
+    }
+    Ok(Template::render(
+        "link",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            total_hits: hits.values().sum::<i64>(),
+            hits: hits,
+            aliases: aliases,
+            can_edit: can_edit,
+            hostname: &config.hostname,
+            csrf_token: csrf::token(&user.session),
+            name: link.name.clone(),
+            link: link,
+        },
+    ))
+}
+
+/// How many links are shown when searching
+const SEARCH_RESULTS: i64 = 100;
+
+/// Searches the links by their names, titles, tags, descriptions and URLs
+#[get("/search?<q>&<tag>")]
+pub async fn search(
+    _user: User,
+    mut db: Connection<Db>,
+    q: Option<&str>,
+    tag: Option<&str>,
+) -> Template {
+    let search = LinkSearch {
+        query: q,
+        tags: api::normalize_tags(tag.as_slice()),
+        order: LinkOrder::Relevance,
+        listed_only: false,
+        limit: SEARCH_RESULTS,
+        offset: 0,
+    };
+    let links = Url::search(&mut db, &search).await;
+
+    Template::render(
+        "search",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            links: links,
+            q: q,
+            tag: tag,
+            name: "Search",
+        },
+    )
+}
+
+/// Lists every active session so administrators can log users out
+#[get("/sessions")]
+pub async fn sessions(admin: Admin, mut db: Connection<Db>) -> Template {
+    let sessions: Vec<_> = Session::get_all(&mut db)
+        .await
+        .into_iter()
+        .map(|s| {
+            context! {
+                user_id: s.user_id,
+                created_at: s.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                last_seen: s.last_seen.format("%Y-%m-%d %H:%M").to_string(),
+            }
+        })
+        .collect();
+
+    Template::render(
+        "sessions",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            sessions: sessions,
+            csrf_token: csrf::token(&admin.0.session),
+            name: "Sessions",
+        },
+    )
+}
+
 /// Redirect to the login page if the user is not logged in (so without the
-/// cookie)
-#[get("/", rank = 2)]
-fn no_auth_index() -> Redirect {
-    Redirect::to(uri!(auth::login_page))
+/// cookie), bringing them back to the page they asked for afterwards
+#[catch(401)]
+fn no_auth(req: &Request) -> Redirect {
+    Redirect::to(uri!(auth::login_page(_, Some(req.uri().to_string()))))
 }
 
 /// Adds the endpoints for admin interface
 pub fn stage(route: String) -> AdHoc {
-    AdHoc::on_ignite("Admin Server Initialisation", |rocket| async {
-        rocket.mount(route, routes![index, no_auth_index])
+    AdHoc::on_ignite("Admin Server Initialisation", |rocket| async move {
+        let base = Origin::parse_owned(route.clone()).expect("The admin route must be a path");
+
+        rocket
+            .manage(AdminRoute(base))
+            .mount(&route, routes![index, link, search, sessions])
+            .register(&route, catchers![no_auth])
     })
 }
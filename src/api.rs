@@ -1,111 +1,133 @@
 //! All endpoints and structures used and returned by the API (which requires
 //! authentication to access)
 
+use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use rocket::fairing::AdHoc;
-use rocket::http::CookieJar;
+use rocket::http::{CookieJar, Status};
+use rocket::response::status::NoContent;
+use rocket::response::stream::{Event, EventStream};
 use rocket::response::Redirect;
-use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::State;
+use rocket::serde::{
+    json::{self, Json},
+    Deserialize, Serialize,
+};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Route, Shutdown, State};
 use rocket_db_pools::diesel::prelude::*;
+use rocket_db_pools::diesel::result::DatabaseErrorKind;
 use rocket_db_pools::Connection;
-use validator::{Validate, ValidationError};
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError, ValidationErrors};
 
-use crate::auth::{User, USER_COOKIE};
-use crate::config::AppConfig;
-use crate::database::{self, Db, PrefixLink, Result, Url};
+use crate::admin::AdminRoute;
+use crate::auth::{logout_redirect, Admin, OidcClients, User, USER_COOKIE};
+use crate::cache::{CacheStats, RedirectCache};
+use crate::canonical;
+use crate::config::{AppConfig, NameConfig};
+use crate::csrf::{Csrf, CsrfForm, NoFields};
+use crate::database::{
+    self, Db, LinkHits, LinkOrder, LinkSearch, PrefixLink, Result, Session, TeamMember, Url,
+};
+use crate::error::AppError;
+use crate::idempotency::{self, IdempotencyKey, Previous};
+use crate::names;
+use crate::notify::LinkEvents;
+use crate::quotas::{self, QuotaError};
+use crate::ratelimit::{ApiCalls, RateLimit};
+use crate::reserved::ReservedNames;
+use crate::rules::{self, Decision, RuleCache};
 use crate::schema;
+use crate::titles::TitleFetcher;
+use crate::webhooks::{self, LinkEvent};
 
 pub static API_LOCAL: &str = "/api/v1";
 
-/// This stores the name of the value which is invalid and the description of
-/// the error so we can pass it back to the frontend to give more interactive
-/// errors.
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct FormErrorPair {
-    name: String,
-    description: String,
-}
-
-impl FormErrorPair {
-    fn from_validation(name: &str, errors: &[ValidationError]) -> Self {
-        FormErrorPair {
-            name: name.to_string(),
-            description: errors
-                .iter()
-                .filter_map(|err| err.message.clone().map(|m| m.to_string()))
-                .collect::<Vec<String>>()
-                .join(", "),
-        }
-    }
+/// Type which is returned from the "/add" endpoint
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AddPostResponse {
+    /// The shortened URL
+    url: String,
 }
 
-/// Type which is returned from the "/add" endpoint
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct AddPostResponse {
-    success: bool,
-    form_errors: Vec<FormErrorPair>,
-    error: Option<String>,
-    url: Option<String>,
-    allow_force: bool,
-}
-
-impl AddPostResponse {
-    /// Returns an okay error with the shortened URL
-    fn ok(url: String) -> Self {
-        AddPostResponse {
-            success: true,
-            form_errors: Vec::new(),
-            error: None,
-            url: Some(url),
-            allow_force: false,
-        }
-    }
+/// Data which needs to be given when requesting "/add"
+#[derive(Debug, Validate, Deserialize, Serialize, ToSchema)]
+pub struct AddData {
+    /// The name of the link, a random one is picked if this isn't given
+    #[validate(
+        length(min = 1, message = "The name can't be empty"),
+        custom = "validate_url_name"
+    )]
+    name: Option<String>,
+    /// Where the link redirects to
+    #[validate(url(message = "This isn't a valid URL"))]
+    url: String,
+    /// Replaces the link if the name is taken, or creates it even if there's
+    /// already a link to the URL
+    force: Option<bool>,
+    /// The team which should own the link, otherwise it is owned by the user
+    team: Option<i32>,
+    /// What the link is for, the title of the page is used if this isn't
+    /// given and fetching titles is turned on
+    #[validate(length(max = 200, message = "The title can't be longer than 200 characters"))]
+    title: Option<String>,
+    #[validate(length(
+        max = 2000,
+        message = "The description can't be longer than 2000 characters"
+    ))]
+    description: Option<String>,
+    /// Labels used to find the link, these are stored in lower case
+    #[validate(custom = "validate_tags")]
+    tags: Option<Vec<String>>,
+    /// Whether the link is shown in the directory, new links are unless this
+    /// is false
+    listed: Option<bool>,
+}
 
-    /// Asks the frontend to prompt the user with a warning page, (meaning if
-    /// they run with force the request would succeed)
-    fn dialog(message: &str, form_errors: Option<Vec<FormErrorPair>>) -> Self {
-        AddPostResponse {
-            success: false,
-            form_errors: form_errors.unwrap_or_default(),
-            error: Some(message.to_string()),
-            url: None,
-            allow_force: true,
+/// Makes sure tags are single words which aren't too long
+fn validate_tags(tags: &[String]) -> Result<(), ValidationError> {
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.chars().count() > 50 {
+            return Err(ValidationError::new(
+                "Tags have to be between 1 and 50 characters",
+            ));
         }
-    }
-
-    /// Returns a error response
-    fn error(message: &str, form_errors: Option<Vec<FormErrorPair>>) -> Self {
-        AddPostResponse {
-            success: false,
-            form_errors: form_errors.unwrap_or_default(),
-            error: Some(message.to_string()),
-            url: None,
-            allow_force: false,
+        if !tag
+            .chars()
+            .all(|x| char::is_alphanumeric(x) || x == '-' || x == '_')
+        {
+            return Err(ValidationError::new("Invalid characters in tag!"));
         }
     }
+
+    Ok(())
 }
 
-/// Data which needs to be given when requesting "/add"
-#[derive(Debug, Validate, Deserialize, Serialize)]
-struct AddData {
-    #[validate(length(min = 1), custom = "validate_url_name")]
-    name: Option<String>,
-    #[validate(url)]
-    url: String,
-    force: Option<bool>,
+/// Returns the text unless it is empty, which clears a title or description
+fn non_empty(text: &str) -> Option<&str> {
+    Some(text.trim()).filter(|t| !t.is_empty())
+}
+
+/// Returns the tags in the form they are stored and searched for, in lower
+/// case without duplicates
+pub fn normalize_tags<S: AsRef<str>>(tags: &[S]) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|t| t.as_ref().trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+    tags
 }
 
 /// Validates a valid shorted URL name, making sure it doesn't have any
-/// invalid characters.
+/// invalid characters. Whether the name is reserved is checked separately, as
+/// that depends on what is mounted, see [`ReservedNames`].
 fn validate_url_name(name: &str) -> Result<(), ValidationError> {
-    let forbidden_names = ["api", "admin", "js", "css", "login", "callback"];
-
-    if forbidden_names.into_iter().any(|x| name.eq(x)) {
-        return Err(ValidationError::new("Forbidden name"));
-    }
-
     let valid_name = name
         .chars()
         .all(|x| char::is_alphanumeric(x) || x == '-' || x == '_');
@@ -121,8 +143,38 @@ enum AddResultError {
     Error(diesel::result::Error),
     FailedGen,
     NameExists,
+    /// The name looks like the name of an existing link
+    NameConfusable(String),
+    /// The name isn't allowed by the name settings, for the given reason
+    InvalidName(String),
     UrlExists(String),
-    UnauthorisedLink,
+    UnauthorisedLink(String),
+    UnauthorisedEdit,
+    UnauthorisedTeam,
+    /// The link an alias was being added to doesn't exist
+    NoSuchLink,
+    QuotaExceeded(String),
+    /// The `Idempotency-Key` was already used for a different request
+    KeyReused,
+}
+
+impl AddResultError {
+    /// Explains why a new name can't be used, for checking it while typing
+    fn reason(self) -> String {
+        match self {
+            AddResultError::InvalidName(reason) | AddResultError::UnauthorisedLink(reason) => {
+                reason
+            }
+            AddResultError::NameConfusable(name) => {
+                format!("Looks too much like the existing link '{}'", name)
+            }
+            AddResultError::Error(e) => {
+                error!("Could not check the name: {}", e);
+                "Could not check the name".to_string()
+            }
+            _ => "This name can't be used".to_string(),
+        }
+    }
 }
 
 impl From<diesel::result::Error> for AddResultError {
@@ -131,8 +183,78 @@ impl From<diesel::result::Error> for AddResultError {
     }
 }
 
+impl From<AddResultError> for AppError {
+    fn from(value: AddResultError) -> Self {
+        match value {
+            AddResultError::UnauthorisedLink(reason) => AppError::new(
+                Status::Forbidden,
+                format!("You do not have permission to create this link. {}", reason),
+            ),
+            AddResultError::UnauthorisedEdit => AppError::new(
+                Status::Forbidden,
+                "You do not have permission to change this link",
+            ),
+            AddResultError::NoSuchLink => {
+                AppError::new(Status::NotFound, "This link does not exist")
+            }
+            AddResultError::UnauthorisedTeam => {
+                AppError::new(Status::Forbidden, "You are not a member of this team")
+            }
+            AddResultError::KeyReused => AppError::new(
+                Status::UnprocessableEntity,
+                "This Idempotency-Key has already been used for a different request",
+            ),
+            AddResultError::QuotaExceeded(message) => {
+                AppError::new(Status::Forbidden, message).with_title("Quota Exceeded")
+            }
+            AddResultError::NameExists => AppError::new(
+                Status::Conflict,
+                "The name already exists. Would you like to override?",
+            )
+            .allow_force(),
+            AddResultError::NameConfusable(name) => AppError::new(
+                Status::Conflict,
+                format!("The name looks too much like the existing link '{}'", name),
+            ),
+            AddResultError::InvalidName(reason) => {
+                let mut error = ValidationError::new("invalid_name");
+                error.message = Some(reason.into());
+                let mut errors = ValidationErrors::new();
+                errors.add("name", error);
+                AppError::validation(&errors)
+            }
+            AddResultError::UrlExists(name) => AppError::new(
+                Status::Conflict,
+                format!(
+                    "This already has a link with name '{}'. Are you sure you want to create a new link?",
+                    name
+                ),
+            )
+            .allow_force(),
+            AddResultError::Error(e) => {
+                error!("Could not create the link: {}", e);
+                AppError::internal("Could not create the link")
+            }
+            AddResultError::FailedGen => AppError::internal("Could not create the link"),
+        }
+    }
+}
+
+impl From<QuotaError> for AddResultError {
+    fn from(value: QuotaError) -> Self {
+        match value {
+            QuotaError::Exceeded(message) => AddResultError::QuotaExceeded(message),
+            QuotaError::Error(e) => AddResultError::Error(e),
+        }
+    }
+}
+
 /// Generates a random 3 letter name for the shorted URL when one is not given
-async fn gen_random_name(conn: &mut Connection<Db>) -> Result<String, AddResultError> {
+async fn gen_random_name(
+    conn: &mut Connection<Db>,
+    config: &NameConfig,
+    reserved: &ReservedNames,
+) -> Result<String, AddResultError> {
     // Try 5 times to generate a name before giving up
     for _ in 0..5 {
         let name: String = rand::thread_rng()
@@ -141,7 +263,12 @@ async fn gen_random_name(conn: &mut Connection<Db>) -> Result<String, AddResultE
             .map(char::from)
             .collect();
 
-        if !Url::exists(conn, &name).await {
+        let normalized = names::normalize(config, &name);
+        let confusable = config.reject_confusables
+            && Url::confusable_with(conn, &normalized, &names::skeleton(&normalized))
+                .await
+                .is_some();
+        if !confusable && !reserved.contains(&normalized) && !Url::exists(conn, &normalized).await {
             return Ok(name);
         }
     }
@@ -154,120 +281,984 @@ async fn gen_random_name(conn: &mut Connection<Db>) -> Result<String, AddResultE
 /// to the user
 async fn should_update(
     conn: &mut Connection<Db>,
-    name: &str,
-    url: &str,
+    normalized: &str,
+    canonical_url: &str,
     force: bool,
 ) -> Result<bool, AddResultError> {
-    let other_link = Url::from_url(conn, url).await;
-    let name_exists: bool = Url::exists(conn, name).await;
+    let other_link = Url::from_url(conn, canonical_url).await;
+    let name_exists: bool = Url::exists(conn, normalized).await;
 
     if force {
-        Ok(name_exists | other_link.is_some())
+        Ok(name_exists)
     } else if name_exists {
-        return Err(AddResultError::NameExists);
+        Err(AddResultError::NameExists)
     } else if let Some(link) = other_link {
-        return Err(AddResultError::UrlExists(link.name));
+        Err(AddResultError::UrlExists(link.name))
     } else {
         Ok(false)
     }
 }
 
+/// Checks that the user can create a link or alias with a new name, returning
+/// its normalised form and the rule which allows it
+async fn check_new_name(
+    conn: &mut Connection<Db>,
+    config: &AppConfig,
+    reserved: &ReservedNames,
+    patterns: &RuleCache,
+    user: &User,
+    name: &str,
+) -> Result<(String, PrefixLink), AddResultError> {
+    names::check(&config.names, name).map_err(AddResultError::InvalidName)?;
+    let normalized = names::normalize(&config.names, name);
+    if reserved.contains(&normalized) {
+        return Err(AddResultError::InvalidName(
+            "This name is used by the application".to_string(),
+        ));
+    }
+
+    // Check if the user has permission to create a link with this name, deny
+    // rules also apply to the name as it is looked up so they can't be got
+    // around by e.g. changing the case
+    let prefixes = PrefixLink::get_all(conn, &user.id).await;
+    let rule = rules::allowed_by(patterns, &prefixes, name)
+        .map_err(|d| AddResultError::UnauthorisedLink(d.reason()))?
+        .clone();
+    if let d @ Decision::Denied(_) = rules::check(patterns, &prefixes, &normalized) {
+        return Err(AddResultError::UnauthorisedLink(d.reason()));
+    }
+
+    Ok((normalized, rule))
+}
+
+/// Returns whether the user could create a link with a name which isn't taken
+pub async fn can_create(
+    conn: &mut Connection<Db>,
+    config: &AppConfig,
+    reserved: &ReservedNames,
+    patterns: &RuleCache,
+    user: &User,
+    name: &str,
+) -> bool {
+    if validate_url_name(name).is_err() {
+        return false;
+    }
+
+    match check_new_name(conn, config, reserved, patterns, user, name).await {
+        Ok((normalized, _)) => check_confusable(conn, &config.names, &normalized)
+            .await
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Fails if a new name looks like the name of an existing link
+async fn check_confusable(
+    conn: &mut Connection<Db>,
+    config: &NameConfig,
+    normalized: &str,
+) -> Result<(), AddResultError> {
+    if !config.reject_confusables {
+        return Ok(());
+    }
+
+    let skeleton = names::skeleton(normalized);
+    match Url::confusable_with(conn, normalized, &skeleton).await {
+        Some(other) => Err(AddResultError::NameConfusable(other)),
+        None => Ok(()),
+    }
+}
+
+/// Maps a failed insert to the error for the user
+fn insert_error(e: diesel::result::Error) -> AddResultError {
+    match e {
+        // Someone else took the name since we checked
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            AddResultError::NameExists
+        }
+        e => AddResultError::Error(e),
+    }
+}
+
+/// What happened when adding a link
+enum Added {
+    Link(String),
+    /// The request was a retry, and this is the response to the original
+    Replayed(String),
+}
+
+/// Creates or updates the link, returning its name
+async fn add_link(
+    conn: &mut Connection<Db>,
+    config: &AppConfig,
+    reserved: &ReservedNames,
+    patterns: &RuleCache,
+    user: &User,
+    info: &AddData,
+) -> Result<String, AddResultError> {
+    if let Some(team) = info.team {
+        if TeamMember::get(conn, team, &user.id).await.is_none() {
+            return Err(AddResultError::UnauthorisedTeam);
+        }
+    }
+
+    let canonical_url = canonical::canonicalize(&config.urls, &info.url);
+    let (name, update, rule) = match &info.name {
+        Some(name) => {
+            let (normalized, rule) =
+                check_new_name(conn, config, reserved, patterns, user, name).await?;
+
+            let force = info.force.unwrap_or(false);
+            let up = should_update(conn, &normalized, &canonical_url, force).await?;
+            if !up {
+                check_confusable(conn, &config.names, &normalized).await?;
+            }
+            (name.clone(), up, Some(rule))
+        }
+        None => {
+            // If it already exists we just want to return that
+            if let Some(link) = Url::from_url(conn, &canonical_url).await {
+                return Ok(link.name);
+            }
+
+            (
+                gen_random_name(conn, &config.names, reserved).await?,
+                false,
+                None,
+            )
+        }
+    };
+    let normalized = names::normalize(&config.names, &name);
+
+    if update {
+        if !Url::user_can_edit(conn, &user.id, config.is_admin(&user.id), &normalized).await {
+            return Err(AddResultError::UnauthorisedEdit);
+        }
+
+        // Changing an alias changes the link it is an alias of, which the
+        // database passes on to the rest of its aliases
+        let existing = Url::get(conn, &normalized)
+            .await
+            .ok_or(diesel::result::Error::NotFound)?;
+        let target = existing.link_name();
+
+        diesel::update(schema::urls::table)
+            .filter(schema::urls::name.eq(target))
+            .set((
+                schema::urls::url.eq(&info.url),
+                schema::urls::canonical_url.eq(&canonical_url),
+            ))
+            .execute(conn)
+            .await?;
+
+        if let Some(team) = info.team {
+            diesel::update(schema::urls::table)
+                .filter(schema::urls::name.eq(target))
+                .set(schema::urls::team_id.eq(team))
+                .execute(conn)
+                .await?;
+        }
+
+        // Anything which isn't given is left as it was
+        if info.title.is_some()
+            || info.description.is_some()
+            || info.tags.is_some()
+            || info.listed.is_some()
+        {
+            diesel::update(schema::urls::table)
+                .filter(schema::urls::name.eq(target))
+                .set((
+                    info.title
+                        .as_ref()
+                        .map(|t| schema::urls::title.eq(non_empty(t))),
+                    info.description
+                        .as_ref()
+                        .map(|d| schema::urls::description.eq(non_empty(d))),
+                    info.tags
+                        .as_ref()
+                        .map(|t| schema::urls::tags.eq(normalize_tags(t))),
+                    info.listed.map(|l| schema::urls::listed.eq(l)),
+                ))
+                .execute(conn)
+                .await?;
+        }
+    } else {
+        // Changing an existing link doesn't count towards the quotas
+        if !config.is_admin(&user.id) {
+            quotas::enforce(conn, patterns, &config.quotas, &user.id, rule.as_ref()).await?;
+        }
+
+        diesel::insert_into(schema::urls::table)
+            .values(database::Url {
+                name: name.clone(),
+                url: info.url.clone(),
+                user_id: Some(user.id.clone()),
+                team_id: info.team,
+                created_at: Utc::now().naive_utc(),
+                skeleton: names::skeleton(&normalized),
+                normalized_name: normalized.clone(),
+                canonical_url,
+                alias_of: None,
+                title: info.title.as_deref().and_then(non_empty).map(String::from),
+                description: info
+                    .description
+                    .as_deref()
+                    .and_then(non_empty)
+                    .map(String::from),
+                tags: normalize_tags(info.tags.as_deref().unwrap_or_default()),
+                listed: info.listed.unwrap_or(true),
+            })
+            .execute(conn)
+            .await
+            .map_err(insert_error)?;
+    }
+
+    let event = match update {
+        true => LinkEvent::Updated,
+        false => LinkEvent::Created,
+    };
+    let link: Url = schema::urls::table
+        .filter(schema::urls::normalized_name.eq(&normalized))
+        .first(conn)
+        .await?;
+    webhooks::raise(conn, event, &link, &user.id).await?;
+
+    // This is the existing link's name when it was written differently
+    Ok(link.name)
+}
+
 /// Endpoint for adding a shortened URL
+///
+/// Requests with an `Idempotency-Key` header can be retried safely, as
+/// retries get the original response.
+#[utoipa::path(
+    tag = "links",
+    request_body = AddData,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes the request safe to retry"),
+        ("X-CSRF-Token" = String, Header, description = "The session's CSRF token, needed when using the session cookie"),
+    ),
+    responses(
+        (status = 200, description = "The link was created", body = AddPostResponse),
+        (status = 400, description = "The Idempotency-Key is invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not allowed to create the link, over a quota, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The name or URL is already used, which `force` overrides, or the name looks like an existing one", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "The request is invalid, or the Idempotency-Key was used for a different request", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
 #[post("/add", data = "<info>")]
-async fn add<'r>(
+#[allow(clippy::too_many_arguments)]
+async fn add(
+    _limit: RateLimit<ApiCalls>,
     config: &State<AppConfig>,
-    mut db: Connection<Db>,
+    reserved: &State<ReservedNames>,
+    patterns: &State<RuleCache>,
+    cache: &State<RedirectCache>,
+    titles: &State<TitleFetcher>,
     user: User,
+    mut db: Connection<Db>,
+    _csrf: Csrf,
+    key: IdempotencyKey,
     info: Json<AddData>,
-) -> Json<AddPostResponse> {
-    if let Err(e) = info.validate() {
-        let errors = e
-            .field_errors()
-            .iter()
-            .map(|(name, errors)| FormErrorPair::from_validation(name, errors))
-            .collect();
+) -> Result<Json<AddPostResponse>, AppError> {
+    info.validate().map_err(|e| AppError::validation(&e))?;
+    let (url, has_title) = (info.url.clone(), info.title.is_some());
+
+    let request_hash = match json::to_string(&*info) {
+        Ok(body) => idempotency::request_hash(&body),
+        Err(e) => {
+            error!("Could not hash the request: {}", e);
+            return Err(AppError::internal("Could not create the link"));
+        }
+    };
+
+    let res = db
+        .transaction(|conn| {
+            Box::pin(async move {
+                if let Some(key) = &key.0 {
+                    let window = config.idempotency_window();
+                    match idempotency::lookup(conn, window, &user.id, key, &request_hash).await? {
+                        Previous::Response(response) => return Ok(Added::Replayed(response)),
+                        Previous::Mismatch => return Err(AddResultError::KeyReused),
+                        Previous::None => {}
+                    }
+                }
+
+                let name = add_link(conn, config, reserved, patterns, &user, &info).await?;
 
-        return Json(AddPostResponse::error("Invalid request", Some(errors)));
+                if let Some(key) = &key.0 {
+                    let response = AddPostResponse {
+                        url: config.hostname.clone() + &name,
+                    };
+                    let response = json::to_string(&response).map_err(|e| {
+                        AddResultError::Error(diesel::result::Error::SerializationError(Box::new(
+                            e,
+                        )))
+                    })?;
+                    idempotency::store(conn, &user.id, key, &request_hash, &response).await?;
+                }
+
+                Ok::<_, AddResultError>(Added::Link(name))
+            })
+        })
+        .await;
+
+    match res? {
+        Added::Link(name) => {
+            cache.invalidate(&names::normalize(&config.names, &name));
+            if !has_title {
+                titles.fetch(&name, &url);
+            }
+            Ok(Json(AddPostResponse {
+                url: config.hostname.clone() + &name,
+            }))
+        }
+        Added::Replayed(response) => match json::from_str(&response) {
+            Ok(response) => Ok(Json(response)),
+            Err(e) => {
+                error!("Could not read the stored response: {}", e);
+                Err(AppError::internal("Could not create the link"))
+            }
+        },
     }
+}
 
+/// Data which needs to be given when requesting "/delete"
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteData {
+    name: String,
+}
+
+/// Endpoint for removing a shortened URL
+///
+/// The user has to be allowed to change the link. Removing a link removes its
+/// aliases too, while removing an alias only removes that name.
+#[utoipa::path(
+    tag = "links",
+    request_body = DeleteData,
+    params(
+        ("X-CSRF-Token" = String, Header, description = "The session's CSRF token, needed when using the session cookie"),
+    ),
+    responses(
+        (status = 204, description = "The link was deleted"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not allowed to change the link, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The link doesn't exist", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/delete", data = "<info>")]
+async fn delete(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    cache: &State<RedirectCache>,
+    user: User,
+    mut db: Connection<Db>,
+    _csrf: Csrf,
+    info: Json<DeleteData>,
+) -> Result<NoContent, AppError> {
+    let normalized = names::normalize(&config.names, &info.name);
     let res = db
         .transaction(|mut conn| {
             Box::pin(async move {
-                let (name, update) = match &info.name {
-                    Some(name) => {
-                        // Check if the user has permission to create a link with
-                        // this name
-                        if !PrefixLink::user_can_link(conn, &user.id, name).await {
-                            return Err(AddResultError::UnauthorisedLink);
-                        }
-
-                        let up = should_update(conn, name, &info.url, info.force.unwrap_or(false))
-                            .await?;
-                        (name.clone(), up)
-                    }
-                    None => {
-                        // If it already exists we just want to return that
-                        if let Some(link) = Url::from_url(conn, &info.url).await {
-                            return Ok(link.name);
-                        }
+                let link: Option<Url> = schema::urls::table
+                    .filter(schema::urls::normalized_name.eq(&normalized))
+                    .first(&mut conn)
+                    .await
+                    .optional()?;
 
-                        (gen_random_name(conn).await?, false)
-                    }
+                let Some(link) = link else {
+                    return Ok(Err(AppError::new(
+                        Status::NotFound,
+                        "This link does not exist",
+                    )));
                 };
+                if !Url::user_can_edit(
+                    conn,
+                    &user.id,
+                    config.is_admin(&user.id),
+                    &link.normalized_name,
+                )
+                .await
+                {
+                    return Ok(Err(AppError::new(
+                        Status::Forbidden,
+                        "You do not have permission to change this link",
+                    )));
+                }
 
-                if update {
-                    diesel::update(schema::urls::table)
-                        .filter(schema::urls::name.eq(&name))
-                        .set(schema::urls::url.eq(&info.url))
-                        .execute(&mut conn)
-                        .await?;
-                } else {
-                    diesel::insert_into(schema::urls::table)
-                        .values(database::Url {
-                            name: name.clone(),
-                            url: info.url.clone(),
-                        })
-                        .execute(&mut conn)
-                        .await?;
+                // The link's aliases are deleted along with it, so they are
+                // gone for webhooks and caches too
+                let aliases = Url::aliases(conn, &link.name).await;
+                diesel::delete(schema::urls::table.filter(schema::urls::name.eq(&link.name)))
+                    .execute(&mut conn)
+                    .await?;
+                for deleted in std::iter::once(&link).chain(&aliases) {
+                    webhooks::raise(conn, LinkEvent::Deleted, deleted, &user.id).await?;
                 }
 
-                Ok::<_, AddResultError>(name)
+                info!("{} deleted the link {}", user.id, link.name);
+                Ok::<_, diesel::result::Error>(Ok(std::iter::once(link)
+                    .chain(aliases)
+                    .map(|l| l.normalized_name)
+                    .collect::<Vec<_>>()))
             })
         })
         .await;
 
     match res {
-        Ok(name) => Json(AddPostResponse::ok(config.hostname.clone() + &name)),
-        Err(e) => match e {
-            AddResultError::UnauthorisedLink => Json(AddPostResponse::error(
-                "You do not have permission to create this link",
-                None,
-            )),
-            AddResultError::NameExists => Json(AddPostResponse::dialog(
-                "The name already exists. Would you like to override?",
-                None,
-            )),
-            AddResultError::UrlExists(name) => Json(AddPostResponse::dialog(
-                &format!("This already has a link with name '{}'. Are you sure you want to create a new link?", name),
-                None,
-            )),
-            AddResultError::Error(_) | AddResultError::FailedGen => {
-                Json(AddPostResponse::error("Could not create the link", None))
+        Ok(Ok(deleted)) => {
+            for normalized in &deleted {
+                cache.invalidate(normalized);
             }
+            Ok(NoContent)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(e) => {
+            error!("Could not delete the link: {}", e);
+            Err(AppError::internal("Could not delete the link"))
+        }
+    }
+}
+
+/// Type which is returned from the "/check" endpoint
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct CheckResponse {
+    allowed: bool,
+    /// Which rule allowed or blocked the name
+    reason: String,
+}
+
+/// Checks whether the user could create a link with the given name
+///
+/// This explains which rule allowed or blocked the name, so it can be checked
+/// while typing.
+#[utoipa::path(
+    tag = "links",
+    params(("name", description = "The name of the link")),
+    responses(
+        (status = 200, description = "Whether the name is allowed", body = CheckResponse),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[get("/check?<name>")]
+async fn check(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    reserved: &State<ReservedNames>,
+    patterns: &State<RuleCache>,
+    user: User,
+    mut db: Connection<Db>,
+    name: &str,
+) -> Json<CheckResponse> {
+    if let Err(e) = validate_url_name(name) {
+        return Json(CheckResponse {
+            allowed: false,
+            reason: e.code.to_string(),
+        });
+    }
+
+    // The same checks as when the link is created, so the two can't disagree
+    let res = match check_new_name(&mut db, config, reserved, patterns, &user, name).await {
+        Ok((normalized, rule)) => check_confusable(&mut db, &config.names, &normalized)
+            .await
+            .map(|_| rule),
+        Err(e) => Err(e),
+    };
+
+    Json(match res {
+        Ok(rule) => CheckResponse {
+            allowed: true,
+            reason: Decision::Allowed(rules::describe(&rule)).reason(),
+        },
+        Err(e) => CheckResponse {
+            allowed: false,
+            reason: e.reason(),
         },
+    })
+}
+
+/// What is shown about a link when searching
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LinkSummary {
+    name: String,
+    /// Where the link redirects to
+    url: String,
+    title: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    /// The user who created the link
+    user_id: Option<String>,
+    /// The team which owns the link, if any
+    team_id: Option<i32>,
+    /// Whether the link is shown in the directory
+    listed: bool,
+}
+
+impl From<Url> for LinkSummary {
+    fn from(link: Url) -> Self {
+        LinkSummary {
+            name: link.name,
+            url: link.url,
+            title: link.title,
+            description: link.description,
+            tags: link.tags,
+            user_id: link.user_id,
+            team_id: link.team_id,
+            listed: link.listed,
+        }
     }
 }
 
-/// Logs the user out
-#[post("/logout")]
-fn logout(jar: &CookieJar<'_>) -> Redirect {
+/// A link along with its aliases
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LinkDetails {
+    #[serde(flatten)]
+    link: LinkSummary,
+    /// The other names which redirect to the same place
+    aliases: Vec<String>,
+    /// How many times the link has been followed, by any of its names. Counts
+    /// are saved every few seconds, so the latest visits may be missing.
+    hits: i64,
+}
+
+/// The most links which are returned by a search
+const MAX_SEARCH_RESULTS: i64 = 200;
+
+/// Searches the links
+///
+/// Links are matched by their names, titles, tags, descriptions and URLs,
+/// with the best matches first unless another order is picked. The query can
+/// use quotes for phrases, `or` and `-` to leave words out. Without a query
+/// the newest links come first.
+#[utoipa::path(
+    tag = "links",
+    params(
+        ("q" = Option<String>, Query, description = "The words to search for"),
+        ("tag" = Option<Vec<String>>, Query, description = "Only return links with all of these tags"),
+        ("sort" = Option<LinkOrder>, Query, description = "The order of the links"),
+        ("limit" = Option<i64>, Query, description = "The most links to return, up to 200 (the default is 50)"),
+        ("offset" = Option<i64>, Query, description = "How many links to skip"),
+    ),
+    responses(
+        (status = 200, description = "The links which match", body = [LinkSummary]),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[get("/links?<q>&<tag>&<sort>&<limit>&<offset>")]
+#[allow(clippy::too_many_arguments)]
+async fn search(
+    _limit: RateLimit<ApiCalls>,
+    _user: User,
+    mut db: Connection<Db>,
+    q: Option<&str>,
+    tag: Vec<&str>,
+    sort: Option<LinkOrder>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Json<Vec<LinkSummary>> {
+    let search = LinkSearch {
+        query: q,
+        tags: normalize_tags(&tag),
+        order: sort.unwrap_or_default(),
+        listed_only: false,
+        limit: limit.unwrap_or(50).clamp(1, MAX_SEARCH_RESULTS),
+        offset: offset.unwrap_or(0).max(0),
+    };
+
+    let links = Url::search(&mut db, &search).await;
+    Json(links.into_iter().map(LinkSummary::from).collect())
+}
+
+/// Returns a link and its aliases
+///
+/// Any of the link's names can be given, including its aliases.
+#[utoipa::path(
+    tag = "links",
+    params(("name", description = "The name of the link, or one of its aliases")),
+    responses(
+        (status = 200, description = "The link", body = LinkDetails),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The link doesn't exist", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[get("/links/<name>")]
+async fn link(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    _user: User,
+    mut db: Connection<Db>,
+    name: &str,
+) -> Result<Json<LinkDetails>, AppError> {
+    let not_found = || AppError::new(Status::NotFound, "This link does not exist");
+    let found = Url::get(&mut db, &names::normalize(&config.names, name))
+        .await
+        .ok_or_else(not_found)?;
+    let link = match &found.alias_of {
+        Some(target) => Url::get(&mut db, &names::normalize(&config.names, target))
+            .await
+            .ok_or_else(not_found)?,
+        None => found,
+    };
+
+    let aliases: Vec<String> = Url::aliases(&mut db, &link.name)
+        .await
+        .into_iter()
+        .map(|a| a.name)
+        .collect();
+    let names: Vec<&str> = std::iter::once(link.name.as_str())
+        .chain(aliases.iter().map(String::as_str))
+        .collect();
+    let hits = LinkHits::get_all(&mut db, &names).await.values().sum();
+    Ok(Json(LinkDetails {
+        aliases,
+        hits,
+        link: link.into(),
+    }))
+}
+
+/// Data which needs to be given when adding an alias
+#[derive(Debug, Validate, Deserialize, Serialize, ToSchema)]
+pub struct AliasData {
+    /// The new name for the link
+    #[validate(
+        length(min = 1, message = "The name can't be empty"),
+        custom = "validate_url_name"
+    )]
+    name: String,
+}
+
+/// Adds another name for a link
+///
+/// The alias redirects to wherever the link does, including after the link is
+/// changed, and is removed along with it. Aliases are removed on their own
+/// with "/delete".
+#[utoipa::path(
+    tag = "links",
+    request_body = AliasData,
+    params(
+        ("link", description = "The name of the link, or one of its aliases"),
+        ("X-CSRF-Token" = String, Header, description = "The session's CSRF token, needed when using the session cookie"),
+    ),
+    responses(
+        (status = 200, description = "The alias was created", body = AddPostResponse),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not allowed to change the link or use the name, over a quota, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The link doesn't exist", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The name is already used, or looks like an existing one", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "The request is invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/links/<link>/aliases", data = "<info>")]
+#[allow(clippy::too_many_arguments)]
+async fn add_alias(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    reserved: &State<ReservedNames>,
+    patterns: &State<RuleCache>,
+    cache: &State<RedirectCache>,
+    user: User,
+    mut db: Connection<Db>,
+    _csrf: Csrf,
+    link: &str,
+    info: Json<AliasData>,
+) -> Result<Json<AddPostResponse>, AppError> {
+    info.validate().map_err(|e| AppError::validation(&e))?;
+    let target = names::normalize(&config.names, link);
+    let name = info.name.clone();
+
+    let res = db
+        .transaction(|conn| {
+            Box::pin(async move {
+                // Aliases of aliases belong to the same link
+                let found = Url::get(conn, &target)
+                    .await
+                    .ok_or(AddResultError::NoSuchLink)?;
+                let link = match &found.alias_of {
+                    Some(name) => Url::get(conn, &names::normalize(&config.names, name))
+                        .await
+                        .ok_or(AddResultError::NoSuchLink)?,
+                    None => found,
+                };
+                if !Url::user_can_edit(
+                    conn,
+                    &user.id,
+                    config.is_admin(&user.id),
+                    &link.normalized_name,
+                )
+                .await
+                {
+                    return Err(AddResultError::UnauthorisedEdit);
+                }
+
+                let (normalized, rule) =
+                    check_new_name(conn, config, reserved, patterns, &user, &info.name).await?;
+                if Url::exists(conn, &normalized).await {
+                    return Err(AddResultError::NameExists);
+                }
+                check_confusable(conn, &config.names, &normalized).await?;
+                if !config.is_admin(&user.id) {
+                    quotas::enforce(conn, patterns, &config.quotas, &user.id, Some(&rule)).await?;
+                }
+
+                let alias = database::Url {
+                    name: info.name.clone(),
+                    url: link.url,
+                    user_id: Some(user.id.clone()),
+                    team_id: link.team_id,
+                    created_at: Utc::now().naive_utc(),
+                    skeleton: names::skeleton(&normalized),
+                    normalized_name: normalized.clone(),
+                    canonical_url: link.canonical_url,
+                    alias_of: Some(link.name),
+                    // Aliases use the link's
+                    title: None,
+                    description: None,
+                    tags: Vec::new(),
+                    // Only used to suggest the alias, aliases aren't in the
+                    // directory themselves
+                    listed: link.listed,
+                };
+                diesel::insert_into(schema::urls::table)
+                    .values(&alias)
+                    .execute(conn)
+                    .await
+                    .map_err(insert_error)?;
+                webhooks::raise(conn, LinkEvent::Created, &alias, &user.id).await?;
+
+                info!(
+                    "{} added the alias {} for {}",
+                    user.id,
+                    alias.name,
+                    alias.link_name()
+                );
+                Ok::<_, AddResultError>(normalized)
+            })
+        })
+        .await;
+
+    match res {
+        Ok(normalized) => {
+            cache.invalidate(&normalized);
+            Ok(Json(AddPostResponse {
+                url: config.hostname.clone() + &name,
+            }))
+        }
+        // Aliases can't replace existing names
+        Err(AddResultError::NameExists) => {
+            Err(AppError::new(Status::Conflict, "The name is already used"))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Logs the user out, both here and at the authentication server. This
+/// works from the session cookie alone, so a session which has already
+/// expired still has its cookie cleared.
+#[utoipa::path(
+    tag = "sessions",
+    request_body(content = NoFields, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the authentication server, or the logged out page"),
+        (status = 403, description = "The CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/logout", data = "<_csrf>")]
+async fn logout(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    mut db: Connection<Db>,
+    jar: &CookieJar<'_>,
+    clients: Option<&State<OidcClients>>,
+    _csrf: CsrfForm<NoFields>,
+) -> Redirect {
+    let session_id = jar
+        .get_private(USER_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+
+    let session = match &session_id {
+        Some(id) => Session::get(&mut db, id).await,
+        None => None,
+    };
+
+    if let Some(id) = &session_id {
+        if let Err(e) = Session::delete(&mut db, id).await {
+            error!("Could not remove session: {}", e);
+        }
+    }
     jar.remove_private(USER_COOKIE);
-    Redirect::to(uri!(crate::auth::login_page))
+
+    logout_redirect(config, clients.map(|c| c.inner()), session.as_ref()).await
+}
+
+/// Logs the user out of every session they have, not just this one
+#[utoipa::path(
+    tag = "sessions",
+    request_body(content = NoFields, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the authentication server, or the logged out page"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/logout/all", data = "<_csrf>")]
+async fn logout_all(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    user: User,
+    mut db: Connection<Db>,
+    jar: &CookieJar<'_>,
+    clients: Option<&State<OidcClients>>,
+    _csrf: CsrfForm<NoFields>,
+) -> Redirect {
+    let session = Session::get(&mut db, &user.session).await;
+
+    if let Err(e) = Session::delete_all(&mut db, &user.id).await {
+        error!("Could not remove sessions: {}", e);
+    }
+    jar.remove_private(USER_COOKIE);
+
+    logout_redirect(config, clients.map(|c| c.inner()), session.as_ref()).await
+}
+
+/// Data which needs to be given when requesting "/sessions/revoke"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct RevokeData<'r> {
+    user_id: &'r str,
+}
+
+/// Allows an administrator to log a user out of all their sessions
+#[utoipa::path(
+    tag = "sessions",
+    request_body(content = RevokeData, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the sessions page"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/sessions/revoke", data = "<info>")]
+async fn revoke_sessions(
+    _limit: RateLimit<ApiCalls>,
+    admin: Admin,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    info: CsrfForm<RevokeData<'_>>,
+) -> Result<Redirect, AppError> {
+    info!("{} revoked all sessions for {}", admin.0.id, info.user_id);
+
+    Session::delete_all(&mut db, info.user_id)
+        .await
+        .map_err(|e| {
+            error!("Could not remove sessions: {}", e);
+            AppError::internal("Could not remove the sessions")
+        })?;
+
+    Ok(Redirect::to(uri!(
+        admin_route.0.clone(),
+        crate::admin::sessions()
+    )))
+}
+
+/// Shows administrators how well the redirect cache is working
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "The cache's statistics", body = CacheStats),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[get("/stats/cache")]
+fn cache_stats(
+    _limit: RateLimit<ApiCalls>,
+    cache: &State<RedirectCache>,
+    _admin: Admin,
+) -> Json<CacheStats> {
+    Json(cache.stats())
+}
+
+/// Streams changes to links as they happen, whichever instance made them
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (
+            status = 200,
+            description = "Server-sent events, each with a change as JSON",
+            body = LinkChange,
+            content_type = "text/event-stream",
+        ),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[get("/links/events")]
+fn link_events(
+    _limit: RateLimit<ApiCalls>,
+    events: &State<LinkEvents>,
+    _admin: Admin,
+    mut end: Shutdown,
+) -> EventStream![] {
+    let mut changes = events.subscribe();
+
+    EventStream! {
+        loop {
+            let change = select! {
+                change = changes.recv() => match change {
+                    Ok(change) => change,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut end => break,
+            };
+
+            yield Event::json(&change);
+        }
+    }
+}
+
+/// Returns the routes which make up the API
+pub fn routes() -> Vec<Route> {
+    routes![
+        add,
+        delete,
+        check,
+        search,
+        link,
+        add_alias,
+        cache_stats,
+        link_events,
+        logout,
+        logout_all,
+        revoke_sessions
+    ]
 }
 
 /// Initialises the API at a given route
 pub fn stage(route: String) -> AdHoc {
     AdHoc::on_ignite("API Server Initialisation", |rocket| async {
-        rocket.mount(route, routes![add, logout])
+        rocket.mount(route, routes())
     })
 }
@@ -1,28 +1,105 @@
 //! Handles authentication with an OIDC server for the admin interfaces
 
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use base64::engine::{general_purpose::URL_SAFE_NO_PAD, Engine};
 use openidconnect::{
-    core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
+    core::{
+        CoreAuthenticationFlow, CoreClient, CoreIdToken, CoreJwsSigningAlgorithm, CoreTokenResponse,
+    },
     reqwest::async_http_client,
-    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, RedirectUrl,
-    TokenResponse,
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, JsonWebKey, LogoutRequest,
+    Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, PostLogoutRedirectUrl,
+    ProviderMetadataWithLogout, RedirectUrl, RefreshToken, TokenResponse,
 };
+use rocket::tokio::{self, sync::RwLock};
 use rocket::{
     fairing::AdHoc,
-    http::{CookieJar, Status},
+    form::Form,
+    http::{uri::Origin, CookieJar, Header, Status},
 };
-use rocket::{http::Cookie, outcome::IntoOutcome};
+use rocket::{http::Cookie, outcome::try_outcome, request::Outcome, State};
 use rocket::{
     http::SameSite,
     request::{self, FromRequest, Request},
 };
-use rocket::{response::Redirect, serde::json, State};
+use rocket::{response::Redirect, serde::json, Either};
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::{context, Template};
 use serde::{Deserialize, Serialize};
 
-use crate::config::AppConfig;
+use crate::config::{AppConfig, AuthProvider, OidcProviderConfig};
+use crate::database::{Db, DisplayName, Session, Team, UsedLogoutToken};
+use crate::error::AppError;
+use crate::local_auth;
+use crate::utils::random_colour;
 
 pub const USER_COOKIE: &str = "user";
 pub const VALIDATOR_COOKIE: &str = "validator";
 
+/// Where users are sent after logging in if they weren't trying to get
+/// anywhere in particular
+pub const DEFAULT_RETURN_TO: &str = "/admin";
+
+/// The shortest amount of time to wait before retrying discovery after it
+/// fails, this is doubled after each consecutive failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The longest amount of time to wait between discovery attempts when the
+/// authentication server is unreachable
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// The event which has to be included in a back-channel logout token
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+/// How long after being issued a back-channel logout token is accepted
+const MAX_LOGOUT_TOKEN_AGE: i64 = 300;
+/// How far the authentication server's clock may be from ours, in seconds
+const MAX_CLOCK_SKEW: i64 = 60;
+
+/// A client for an authentication server, along with the provider metadata
+/// it was created from (which includes the JWKS and logout endpoint)
+#[derive(Clone)]
+pub struct Provider {
+    /// The name the provider was given in the configuration
+    pub name: String,
+    pub client: CoreClient,
+    pub client_id: ClientId,
+    pub metadata: ProviderMetadataWithLogout,
+}
+
+/// Shared handle to the OIDC clients, each of which is only populated once
+/// discovery with its authentication server has succeeded.
+///
+/// This is stored in the global state and kept up to date by background
+/// tasks, so that the provider metadata and JWKS are periodically refreshed.
+#[derive(Clone, Default)]
+pub struct OidcClients(Arc<RwLock<HashMap<String, Provider>>>);
+
+impl OidcClients {
+    /// Returns the client for the provider with the given name, or `None` if
+    /// discovery has not yet succeeded
+    pub async fn get(&self, name: &str) -> Option<Provider> {
+        self.0.read().await.get(name).cloned()
+    }
+
+    /// Returns the client for the provider with the given issuer, which is
+    /// how back-channel requests tell us who they are from
+    async fn get_by_issuer(&self, issuer: &str) -> Option<Provider> {
+        self.0
+            .read()
+            .await
+            .values()
+            .find(|p| p.metadata.issuer().as_str() == issuer)
+            .cloned()
+    }
+
+    async fn set(&self, provider: Provider) {
+        self.0.write().await.insert(provider.name.clone(), provider);
+    }
+}
+
 /// Inspired by https://github.com/csssuf/rocket_oidc
 ///
 /// Stores the information required to validate a connection to the
@@ -32,34 +109,53 @@ pub struct OidcValidator {
     pub auth_url: String,
     pub csrf_token: CsrfToken,
     pub nonce: Nonce,
+    pub pkce_verifier: PkceCodeVerifier,
+    /// Where to send the user once they have logged in
+    pub return_to: Option<String>,
+    /// Name of the provider the user is logging in with
+    pub provider: String,
 }
 impl OidcValidator {
-    pub fn new(client: &CoreClient) -> Self {
-        let (auth_url, csrf_token, nonce) = client
+    pub fn new(provider: &Provider, return_to: Option<String>) -> Self {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (auth_url, csrf_token, nonce) = provider
+            .client
             .authorize_url(
                 CoreAuthenticationFlow::AuthorizationCode,
                 CsrfToken::new_random,
                 Nonce::new_random,
             )
+            .set_pkce_challenge(pkce_challenge)
             .url();
 
         OidcValidator {
             auth_url: auth_url.to_string(),
             csrf_token,
             nonce,
+            pkce_verifier,
+            return_to,
+            provider: provider.name.clone(),
         }
     }
 
+    /// Returns whether the `state` given back by the authentication server
+    /// matches the CSRF token we sent with the user
+    pub fn state_matches(&self, state: &str) -> bool {
+        self.csrf_token.secret() == state
+    }
+
     /// Once the user returns from the authentication server, we need to
-    /// validate and extract the user's ID from it
+    /// validate and extract the user's ID from it, along with the name they
+    /// can be shown by if the server gave one
     pub async fn verify(
-        &self,
-        client: &CoreClient,
+        self,
+        provider: &Provider,
         code: &str,
-    ) -> Result<Option<User>, Box<dyn std::error::Error>> {
+    ) -> Result<Option<(Session, Option<String>)>> {
+        let client = &provider.client;
         let tr = client
             .exchange_code(AuthorizationCode::new(code.to_string()))
-            // .set_pkce_verifier(self.pkce_verifier)
+            .set_pkce_verifier(self.pkce_verifier)
             .request_async(async_http_client)
             .await?;
 
@@ -70,30 +166,351 @@ impl OidcValidator {
 
         let claims = id_token.claims(&client.id_token_verifier(), &self.nonce)?;
 
-        Ok(Some(User {
-            id: claims.subject().to_string(),
-        }))
+        // The session ID isn't one of the claims openidconnect exposes, but
+        // the token has already been verified so we can read it directly
+        let sid = jwt_payload::<SessionIdClaim>(&id_token.to_string())?.sid;
+
+        let display_name = claims
+            .preferred_username()
+            .map(|u| u.to_string())
+            .or_else(|| claims.email().map(|e| e.to_string()));
+
+        let session = Session::new(
+            provider.user_id(claims.subject()),
+            tr.refresh_token().map(|t| t.secret().clone()),
+            token_lifetime(&tr),
+        )
+        .with_provider(provider.name.clone())
+        .with_id_token(id_token.to_string(), sid);
+        Ok(Some((session, display_name)))
+    }
+}
+
+#[derive(Deserialize)]
+struct SessionIdClaim {
+    sid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IssuerClaim {
+    iss: String,
+}
+
+/// Returns the groups listed in the given claim of a (verified) ID token, or
+/// `None` if the token doesn't have a list of groups in that claim
+fn token_groups(id_token: &str, claim: &str) -> Option<Vec<String>> {
+    jwt_payload::<json::Value>(id_token)
+        .ok()
+        .and_then(|claims| claims.get(claim).cloned())
+        .and_then(|groups| json::from_value(groups).ok())
+}
+
+/// Decodes the payload of a JWT without verifying it
+fn jwt_payload<T: for<'de> Deserialize<'de>>(token: &str) -> Result<T> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| anyhow::anyhow!("Malformed JWT"))?;
+
+    Ok(json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?)
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: CoreJwsSigningAlgorithm,
+    kid: Option<String>,
+}
+
+/// The audience of a JWT can either be a single value or a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &ClientId) -> bool {
+        let client_id = client_id.as_str();
+        match self {
+            Audience::Single(aud) => aud == client_id,
+            Audience::Multiple(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+/// The claims sent by the authentication server in a back-channel logout
+/// token, see https://openid.net/specs/openid-connect-backchannel-1_0.html
+#[derive(Deserialize)]
+struct LogoutClaims {
+    iss: String,
+    aud: Audience,
+    sub: Option<String>,
+    sid: Option<String>,
+    events: json::Value,
+    nonce: Option<String>,
+    iat: i64,
+    exp: Option<i64>,
+    jti: Option<String>,
+}
+
+/// The sessions a verified back-channel logout token applies to
+struct LogoutTarget {
+    user_id: Option<String>,
+    sid: Option<String>,
+    /// The token's ID, which must only be used once
+    jti: String,
+    /// When the token would stop being accepted, so its ID can be forgotten
+    expires_at: chrono::NaiveDateTime,
+}
+
+impl Provider {
+    /// Returns the ID used for a user of this provider, which is namespaced
+    /// by the issuer so that subjects from different providers can't collide
+    pub fn user_id(&self, subject: &str) -> String {
+        format!("{}|{}", self.metadata.issuer().as_str(), subject)
+    }
+
+    /// Returns the URL to send the user to so they are also logged out of the
+    /// authentication server, if it supports RP-initiated logout
+    pub fn logout_url(&self, config: &AppConfig, id_token: Option<&str>) -> Option<String> {
+        let endpoint = self
+            .metadata
+            .additional_metadata()
+            .end_session_endpoint
+            .clone()?;
+
+        let mut request = LogoutRequest::from(endpoint).set_client_id(self.client_id.clone());
+
+        if let Some(id_token) = id_token.and_then(|t| t.parse::<CoreIdToken>().ok()) {
+            request = request.set_id_token_hint(&id_token);
+        }
+        if let Ok(redirect) = PostLogoutRedirectUrl::new(config.hostname.clone() + "logged-out") {
+            request = request.set_post_logout_redirect_uri(redirect);
+        }
+
+        Some(request.http_get_url().to_string())
+    }
+
+    /// Validates a back-channel logout token, checking its signature against
+    /// the provider's JWKS and that it is recent, and returns the sessions it
+    /// refers to. Checking the token hasn't been used before is left to the
+    /// caller.
+    fn verify_logout_token(&self, token: &str) -> Result<LogoutTarget> {
+        let mut parts = token.split('.');
+        let (Some(encoded_header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("Malformed logout token");
+        };
+
+        let header: JwtHeader = json::from_slice(&URL_SAFE_NO_PAD.decode(encoded_header)?)?;
+        let keys = self.metadata.jwks().keys();
+        if header.kid.is_none() && keys.len() > 1 {
+            anyhow::bail!("Logout token has no key ID, but the provider has several keys");
+        }
+
+        let key = keys
+            .iter()
+            .find(|key| match (&header.kid, key.key_id()) {
+                (Some(kid), Some(key_id)) => kid == key_id.as_str(),
+                (None, _) => true,
+                _ => false,
+            })
+            .ok_or_else(|| anyhow::anyhow!("No matching key for logout token"))?;
+
+        key.verify_signature(
+            &header.alg,
+            format!("{}.{}", encoded_header, payload).as_bytes(),
+            &URL_SAFE_NO_PAD.decode(signature)?,
+        )?;
+
+        let claims: LogoutClaims = jwt_payload(token)?;
+
+        if claims.iss != self.metadata.issuer().as_str() {
+            anyhow::bail!("Logout token has the wrong issuer");
+        }
+        if !claims.aud.contains(&self.client_id) {
+            anyhow::bail!("Logout token was not meant for us");
+        }
+        if claims.events.get(BACKCHANNEL_LOGOUT_EVENT).is_none() {
+            anyhow::bail!("Logout token does not contain a logout event");
+        }
+        if claims.nonce.is_some() {
+            anyhow::bail!("Logout tokens must not contain a nonce");
+        }
+        if claims.sub.is_none() && claims.sid.is_none() {
+            anyhow::bail!("Logout token does not say who to log out");
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        if claims.iat > now + MAX_CLOCK_SKEW {
+            anyhow::bail!("Logout token was issued in the future");
+        }
+        if claims.iat < now - MAX_LOGOUT_TOKEN_AGE - MAX_CLOCK_SKEW {
+            anyhow::bail!("Logout token is too old");
+        }
+        if claims.exp.is_some_and(|exp| exp < now - MAX_CLOCK_SKEW) {
+            anyhow::bail!("Logout token has expired");
+        }
+        let Some(jti) = claims.jti else {
+            anyhow::bail!("Logout token has no ID");
+        };
+
+        let expires_at = chrono::NaiveDateTime::from_timestamp_opt(
+            claims.iat + MAX_LOGOUT_TOKEN_AGE + MAX_CLOCK_SKEW,
+            0,
+        )
+        .ok_or_else(|| anyhow::anyhow!("Logout token has an invalid issue time"))?;
+
+        Ok(LogoutTarget {
+            user_id: claims.sub.map(|sub| self.user_id(&sub)),
+            sid: claims.sid,
+            jti,
+            expires_at,
+        })
     }
 }
 
+/// Returns how long the tokens given by the authentication server are valid
+/// for, and so when the session should next be renewed
+fn token_lifetime(tr: &CoreTokenResponse) -> Option<chrono::Duration> {
+    tr.expires_in()
+        .and_then(|d| chrono::Duration::from_std(d).ok())
+}
+
+/// Renews a session with the authentication server using its refresh token,
+/// returning the new refresh token and how long until it needs renewing again
+async fn refresh_session(
+    client: &CoreClient,
+    refresh_token: &str,
+) -> Result<(Option<String>, Option<chrono::Duration>)> {
+    let tr = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .request_async(async_http_client)
+        .await?;
+
+    let refresh_token = tr
+        .refresh_token()
+        .map(|t| t.secret().clone())
+        .unwrap_or_else(|| refresh_token.to_string());
+
+    Ok((Some(refresh_token), token_lifetime(&tr)))
+}
+
 /// If put in the parameters to an endpoint function, the User has to be logged
 /// in. Stores the Users ID from the authentication server
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
+    /// ID of the session the user is logged in with
+    #[serde(skip)]
+    pub session: String,
 }
 
-/// Allows the User to be automatically extracted from the cookies
+/// Allows the User to be automatically extracted from the session stored in
+/// the cookies. The result is cached for the request, as other guards (such
+/// as [`Admin`]) need the user too.
+///
+/// The session is looked up with a connection from the pool which is handed
+/// back before the guard returns, so handlers should take the user before
+/// their own `Connection<Db>` for it to be reused rather than holding two.
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for User {
     type Error = std::convert::Infallible;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<User, Self::Error> {
-        request
+        let user: &Option<User> = request
+            .local_cache_async(async { User::from_session(request).await })
+            .await;
+
+        match user {
+            Some(user) => Outcome::Success(user.clone()),
+            None => Outcome::Forward(Status::Unauthorized),
+        }
+    }
+}
+
+impl User {
+    /// Looks up the session stored in the cookies, expiring or renewing the
+    /// session as required
+    async fn from_session(request: &Request<'_>) -> Option<User> {
+        let session_id = request
             .cookies()
             .get_private(USER_COOKIE)
-            .and_then(|cookie| json::from_str::<User>(cookie.value()).ok())
-            .or_forward(Status::Unauthorized)
+            .map(|cookie| cookie.value().to_string())?;
+
+        let (Outcome::Success(mut db), Some(config)) = (
+            request.guard::<Connection<Db>>().await,
+            request.rocket().state::<AppConfig>(),
+        ) else {
+            return None;
+        };
+
+        let session = Session::get(&mut db, &session_id).await.filter(|s| {
+            !s.has_expired(
+                config.session_idle_timeout(),
+                config.session_absolute_timeout(),
+            )
+        });
+
+        let Some(session) = session else {
+            let _ = Session::delete(&mut db, &session_id).await;
+            request.cookies().remove_private(USER_COOKIE);
+            return None;
+        };
+
+        if session.needs_refresh() {
+            let client = match (request.rocket().state::<OidcClients>(), &session.provider) {
+                (Some(clients), Some(provider)) => clients.get(provider).await,
+                _ => None,
+            };
+
+            // If the authentication server is unavailable we let the session
+            // continue and try again on the next request
+            if let (Some(client), Some(refresh_token)) = (client, &session.refresh_token) {
+                match refresh_session(&client.client, refresh_token).await {
+                    Ok((refresh_token, expires_in)) => {
+                        let _ =
+                            Session::update_tokens(&mut db, &session.id, refresh_token, expires_in)
+                                .await;
+                    }
+                    Err(e) => {
+                        info!("Could not renew session, logging out: {}", e);
+                        let _ = Session::delete(&mut db, &session.id).await;
+                        request.cookies().remove_private(USER_COOKIE);
+                        return None;
+                    }
+                }
+            }
+        }
+
+        if session.needs_touch() {
+            let _ = Session::touch(&mut db, &session.id).await;
+        }
+
+        Some(User {
+            id: session.user_id,
+            session: session.id,
+        })
+    }
+}
+
+/// Request guard for users which are listed as administrators in the
+/// configuration
+pub struct Admin(pub User);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Admin, Self::Error> {
+        let user = try_outcome!(request.guard::<User>().await);
+
+        match request.rocket().state::<AppConfig>() {
+            Some(config) if config.is_admin(&user.id) => Outcome::Success(Admin(user)),
+            _ => Outcome::Forward(Status::Forbidden),
+        }
     }
 }
 
@@ -101,92 +518,413 @@ impl<'r> FromRequest<'r> for User {
 /// redirect the user or authenticate them.
 ///
 /// This should not be called for each request, instead having a global version
-pub async fn get_client(config: &AppConfig) -> Result<CoreClient, Box<dyn std::error::Error>> {
-    let provider_metadata = CoreProviderMetadata::discover_async(
-        IssuerUrl::new(config.client_url.clone())?,
+pub async fn get_client(config: &AppConfig, provider: &OidcProviderConfig) -> Result<Provider> {
+    let metadata = ProviderMetadataWithLogout::discover_async(
+        IssuerUrl::new(provider.client_url.clone())?,
         async_http_client,
     )
     .await?;
 
+    let client_id = ClientId::new(provider.client_id.to_string());
     let client = CoreClient::from_provider_metadata(
-        provider_metadata,
-        ClientId::new(config.client_id.to_string()),
-        Some(ClientSecret::new(config.client_secret.to_string())),
+        metadata.clone(),
+        client_id.clone(),
+        Some(ClientSecret::new(provider.client_secret.to_string())),
     )
     .set_redirect_uri(RedirectUrl::new(config.hostname.clone() + "callback")?);
 
-    Ok(client)
+    Ok(Provider {
+        name: provider.name.clone(),
+        client,
+        client_id,
+        metadata,
+    })
+}
+
+/// Renders the page shown when logging in fails, with a link to try again
+fn login_error(status: Status, message: &str) -> AppError {
+    AppError::new(status, message)
+        .with_title("Sign in failed")
+        .with_template("error/login")
 }
 
 /// This is called by the authentication server (normally requiring it to be
-/// white-listed) once the user has logged in and allowed the server access
-#[get("/callback?<code>", rank = 2)]
+/// white-listed) once the user has logged in and allowed the server access.
+/// Responses without a state can't be matched to a sign in attempt, so are
+/// refused.
+#[get("/callback?<code>&<state>", rank = 2)]
 async fn callback<'r>(
     jar: &CookieJar<'r>,
-    client: &State<CoreClient>,
+    config: &State<AppConfig>,
+    mut db: Connection<Db>,
+    clients: &State<OidcClients>,
     code: &str,
-) -> Result<Redirect, String> {
+    state: Option<&str>,
+) -> Result<Redirect, AppError> {
     let val = jar
         .get_private(VALIDATOR_COOKIE)
         .and_then(|cookie| json::from_str::<OidcValidator>(cookie.value()).ok());
 
     jar.remove_private(VALIDATOR_COOKIE);
 
-    if let Some(validator) = val {
-        if let Some(user) = validator
-            .verify(client, code)
-            .await
-            .map_err(|e| e.to_string())?
-        {
-            jar.add_private(
-                Cookie::build((USER_COOKIE, json::to_string(&user).unwrap()))
-                    .same_site(SameSite::Lax),
-            );
-            return Ok(Redirect::to(uri!("/admin")));
+    let state = state.ok_or_else(|| {
+        login_error(
+            Status::BadRequest,
+            "The response from the authentication server is missing its state.",
+        )
+    })?;
+
+    let validator = val.ok_or_else(|| {
+        login_error(
+            Status::BadRequest,
+            "Your sign in attempt has expired, please try again.",
+        )
+    })?;
+
+    let return_to = validator.return_to.clone();
+
+    if !validator.state_matches(state) {
+        return Err(login_error(
+            Status::BadRequest,
+            "The response from the authentication server did not match your sign in attempt.",
+        ));
+    }
+
+    let provider = clients.get(&validator.provider).await.ok_or_else(|| {
+        login_error(
+            Status::ServiceUnavailable,
+            "We can't reach the authentication server right now.",
+        )
+    })?;
+
+    let session = validator.verify(&provider, code).await.map_err(|e| {
+        warn!("Could not verify login: {}", e);
+        login_error(
+            Status::Unauthorized,
+            "We could not verify your sign in with the authentication server.",
+        )
+    })?;
+
+    match session {
+        Some((session, display_name)) => {
+            // Good time to clear out any old sessions
+            let _ = Session::delete_expired(
+                &mut db,
+                config.session_idle_timeout(),
+                config.session_absolute_timeout(),
+            )
+            .await;
+
+            session.create(&mut db).await.map_err(|e| {
+                error!("Could not create session: {}", e);
+                login_error(Status::InternalServerError, "Could not start your session.")
+            })?;
+
+            if let Some(name) = display_name {
+                if let Err(e) = DisplayName::set(&mut db, &session.user_id, &name).await {
+                    error!("Could not store the display name: {}", e);
+                }
+            }
+
+            let groups_claim = config
+                .oidc_providers()
+                .into_iter()
+                .find(|p| p.name == provider.name)
+                .map(|p| p.groups_claim);
+
+            // Servers don't always include the groups (such as when they
+            // weren't asked for), which shouldn't remove the user from teams
+            let groups = match (groups_claim, &session.id_token) {
+                (Some(claim), Some(id_token)) => token_groups(id_token, &claim),
+                _ => None,
+            };
+            if let Some(groups) = groups {
+                if let Err(e) = Team::sync_groups(&mut db, &session.user_id, &groups).await {
+                    error!("Could not sync team membership: {}", e);
+                }
+            }
+
+            jar.add_private(Cookie::build((USER_COOKIE, session.id)).same_site(SameSite::Lax));
+            Ok(Redirect::to(
+                return_to.unwrap_or_else(|| DEFAULT_RETURN_TO.to_string()),
+            ))
         }
+        None => Err(login_error(
+            Status::Unauthorized,
+            "The authentication server did not tell us who you are.",
+        )),
     }
+}
+
+/// Called by the authentication server when the login failed or was
+/// cancelled, showing the reason it gave to the user
+#[get("/callback?<error>&<error_description>", rank = 3)]
+fn callback_error(jar: &CookieJar<'_>, error: &str, error_description: Option<&str>) -> AppError {
+    jar.remove_private(VALIDATOR_COOKIE);
 
-    Ok(Redirect::to(uri!("/")))
+    let message = match error_description {
+        Some(description) => format!("{} ({})", description, error),
+        None => format!("The authentication server returned '{}'", error),
+    };
+
+    login_error(Status::Unauthorized, &message)
 }
 
 /// If the user is already logged in, we can just pass them to the admin
 /// interface
 #[get("/callback")]
 fn callback_no_auth(_user: User) -> Redirect {
-    Redirect::to(uri!("/admin"))
+    Redirect::to(DEFAULT_RETURN_TO)
+}
+
+/// Returns `return_to` if it is a path on this site which is safe to send the
+/// user to after logging in, so the login can't be used as an open redirect
+pub fn safe_return_to(return_to: &str) -> Option<String> {
+    // Protocol relative URLs ("//evil.com") or ones which browsers treat as
+    // such ("/\\evil.com") would take the user to another site
+    if !return_to.starts_with('/') || return_to.starts_with("//") || return_to.contains('\\') {
+        return None;
+    }
+
+    Origin::parse(return_to)
+        .ok()
+        .map(|origin| origin.to_string())
 }
 
 /// If the user is already logged in, send them on their way
-#[get("/login")]
-fn login(_user: User) -> Redirect {
-    Redirect::to(uri!("/admin"))
+#[get("/login?<return_to>")]
+fn login(_user: User, return_to: Option<&str>) -> Redirect {
+    Redirect::to(
+        return_to
+            .and_then(safe_return_to)
+            .unwrap_or_else(|| DEFAULT_RETURN_TO.to_string()),
+    )
 }
 
-/// As we are using an OIDC server, we should redirect them there
-#[get("/login", rank = 2)]
-pub fn login_page(jar: &CookieJar, client: &State<CoreClient>) -> Redirect {
-    let validator = OidcValidator::new(client);
+/// As we are using OIDC servers, we should redirect them to the one they
+/// picked, remembering where the user was trying to get to.
+///
+/// If more than one provider is configured and the user hasn't picked one yet,
+/// they are shown a page to choose from.
+#[get("/login?<provider>&<return_to>", rank = 2)]
+pub async fn login_page(
+    jar: &CookieJar<'_>,
+    config: &State<AppConfig>,
+    clients: &State<OidcClients>,
+    provider: Option<&str>,
+    return_to: Option<&str>,
+) -> Result<Either<Redirect, Template>, AppError> {
+    let return_to = return_to.and_then(safe_return_to);
+    let providers = config.oidc_providers();
+
+    let name = match (provider, &providers[..]) {
+        (Some(name), _) => name,
+        (None, [only]) => &only.name,
+        (None, _) => {
+            let providers: Vec<_> = providers
+                .iter()
+                .map(|p| {
+                    context! {
+                        url: uri!(login_page(Some(&p.name), return_to.as_deref())).to_string(),
+                        display_name: p.display_name(),
+                    }
+                })
+                .collect();
+
+            return Ok(Either::Right(Template::render(
+                "providers",
+                context! {
+                    colour: random_colour(),
+                    providers: providers,
+                    name: "Sign in",
+                },
+            )));
+        }
+    };
+
+    if !providers.iter().any(|p| p.name == name) {
+        return Err(AppError::new(
+            Status::NotFound,
+            "There is no authentication server with this name",
+        ));
+    }
+
+    let provider = clients.get(name).await.ok_or_else(|| {
+        AppError::new(
+            Status::ServiceUnavailable,
+            "We can't reach the authentication server right now",
+        )
+    })?;
+
+    let validator = OidcValidator::new(&provider, return_to);
     jar.add_private(
         Cookie::build((VALIDATOR_COOKIE, json::to_string(&validator).unwrap()))
             .same_site(SameSite::Lax),
     );
-    Redirect::to(validator.auth_url)
+    Ok(Either::Left(Redirect::to(validator.auth_url)))
+}
+
+/// Returns where to send the user once they have been logged out locally,
+/// which is the logout page of the authentication server they used, if it has
+/// one
+pub async fn logout_redirect(
+    config: &AppConfig,
+    clients: Option<&OidcClients>,
+    session: Option<&Session>,
+) -> Redirect {
+    let provider = match (clients, session.and_then(|s| s.provider.as_deref())) {
+        (Some(clients), Some(name)) => clients.get(name).await,
+        _ => None,
+    };
+
+    provider
+        .and_then(|p| p.logout_url(config, session.and_then(|s| s.id_token.as_deref())))
+        .map(Redirect::to)
+        .unwrap_or_else(|| Redirect::to(uri!(logged_out)))
 }
 
-/// Creates the client from the configuration and adds it to the global
-/// variables
+/// Shown once the user has been logged out of the application (and the
+/// authentication server)
+#[get("/logged-out")]
+fn logged_out() -> Template {
+    Template::render(
+        "logged_out",
+        context! {
+            colour: random_colour(),
+            name: "Signed out",
+        },
+    )
+}
+
+/// Data sent by the authentication server to "/backchannel-logout"
+#[derive(FromForm)]
+struct BackchannelLogout<'r> {
+    logout_token: &'r str,
+}
+
+/// Responses to back-channel logout requests must not be cached
+#[derive(Responder)]
+struct LogoutAcknowledged(&'static str, Header<'static>);
+
+/// Called directly by the authentication server when a user logs out there,
+/// so that we can remove the matching sessions
+#[post("/backchannel-logout", data = "<info>")]
+async fn backchannel_logout(
+    mut db: Connection<Db>,
+    clients: &State<OidcClients>,
+    info: Form<BackchannelLogout<'_>>,
+) -> Result<LogoutAcknowledged, AppError> {
+    let issuer = jwt_payload::<IssuerClaim>(info.logout_token)
+        .map_err(|_| AppError::new(Status::BadRequest, "The logout token can't be read"))?
+        .iss;
+    let provider = clients
+        .get_by_issuer(&issuer)
+        .await
+        .ok_or_else(|| AppError::new(Status::BadRequest, "The logout token's issuer is unknown"))?;
+
+    let target = provider
+        .verify_logout_token(info.logout_token)
+        .map_err(|e| {
+            warn!("Invalid back-channel logout token: {}", e);
+            AppError::new(Status::BadRequest, "The logout token is invalid")
+        })?;
+
+    let first_use = UsedLogoutToken::record(&mut db, &issuer, &target.jti, target.expires_at)
+        .await
+        .map_err(|e| {
+            error!("Could not record logout token: {}", e);
+            AppError::internal("Could not record the logout token")
+        })?;
+    if !first_use {
+        warn!("Back-channel logout token {} was replayed", target.jti);
+        return Err(AppError::new(
+            Status::BadRequest,
+            "The logout token has already been used",
+        ));
+    }
+
+    let res = match (target.sid, target.user_id) {
+        (Some(sid), _) => Session::delete_by_sid(&mut db, &provider.name, &sid).await,
+        (None, Some(user_id)) => Session::delete_all(&mut db, &user_id).await,
+        (None, None) => Ok(0),
+    };
+
+    res.map_err(|e| {
+        error!("Could not remove sessions: {}", e);
+        AppError::internal("Could not remove the sessions")
+    })?;
+
+    Ok(LogoutAcknowledged(
+        "",
+        Header::new("Cache-Control", "no-store"),
+    ))
+}
+
+/// Keeps the provider's client in `handle` up to date, retrying discovery
+/// with exponential backoff until it succeeds and then refreshing the provider
+/// metadata (and so the JWKS) every `auth_refresh_interval` seconds
+async fn discover(config: AppConfig, provider: OidcProviderConfig, handle: OidcClients) {
+    let mut retry_delay = MIN_RETRY_DELAY;
+
+    loop {
+        let delay = match get_client(&config, &provider).await {
+            Ok(client) => {
+                handle.set(client).await;
+                retry_delay = MIN_RETRY_DELAY;
+                Duration::from_secs(config.auth_refresh_interval)
+            }
+            Err(e) => {
+                warn!(
+                    "Could not reach authentication server '{}', retrying in {}s: {}",
+                    provider.name,
+                    retry_delay.as_secs(),
+                    e
+                );
+                let delay = retry_delay;
+                retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                delay
+            }
+        };
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Adds the login endpoints for the configured authentication provider.
+///
+/// For OIDC this adds the (initially empty) client to the global variables
+/// and starts the background task which discovers the authentication server
+/// once the application has launched, so an outage does not stop redirects
+/// working
 pub fn stage() -> AdHoc {
     AdHoc::on_ignite("Authentication Server Stage", |rocket| async {
         let config: AppConfig = rocket
             .figment()
             .extract()
             .expect("Could not find App Config");
-        let client = get_client(&config)
-            .await
-            .expect("Could not find authentication server");
 
-        rocket
-            .manage(client)
-            .mount("/", routes![login, login_page, callback, callback_no_auth])
+        // The clients are managed even with local accounts, where they stay
+        // empty, as Rocket refuses to launch if any route asks for unmanaged
+        // state
+        let handle = OidcClients::default();
+        let rocket = rocket
+            .manage(handle.clone())
+            .mount("/", routes![login, callback_no_auth, logged_out]);
+
+        match config.auth_provider {
+            AuthProvider::Oidc => rocket
+                .mount(
+                    "/",
+                    routes![login_page, callback, callback_error, backchannel_logout],
+                )
+                .attach(AdHoc::on_liftoff("Authentication Discovery", |_| {
+                    Box::pin(async move {
+                        for provider in config.oidc_providers() {
+                            tokio::spawn(discover(config.clone(), provider, handle.clone()));
+                        }
+                    })
+                })),
+            AuthProvider::Local => rocket.attach(local_auth::stage()),
+        }
     })
 }
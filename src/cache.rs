@@ -0,0 +1,158 @@
+//! Caches where links redirect to, so following a link doesn't need a trip to
+//! the database every time. Names which don't exist are cached too, for a
+//! shorter time, so that guessing names doesn't reach the database either.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use rocket::fairing::AdHoc;
+use rocket::serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::{AppConfig, CacheConfig};
+
+/// A cached lookup, `target` is `None` if the link doesn't exist
+struct Entry {
+    target: Option<String>,
+    expires: Instant,
+}
+
+/// How well the cache is doing since the application started
+#[derive(Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+/// An LRU cache of link names to where they redirect. This is a handle which
+/// can be cloned, so the cache can also be kept up to date from background
+/// tasks.
+#[derive(Clone)]
+pub struct RedirectCache(Arc<CacheInner>);
+
+struct CacheInner {
+    entries: Option<Mutex<LruCache<String, Entry>>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RedirectCache {
+    /// Creates a cache holding up to `capacity` links, where a capacity of
+    /// zero turns the cache off
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        RedirectCache(Arc::new(CacheInner {
+            entries: NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c))),
+            ttl,
+            negative_ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn from_config(config: &CacheConfig) -> Self {
+        RedirectCache::new(
+            config.capacity,
+            Duration::from_secs(config.ttl),
+            Duration::from_secs(config.negative_ttl),
+        )
+    }
+
+    /// Returns the cached target of the link, which is `Some(None)` if the
+    /// link is known not to exist, or `None` if it needs to be looked up
+    pub fn get(&self, name: &str) -> Option<Option<String>> {
+        let entries = self.0.entries.as_ref()?;
+        let mut entries = entries.lock().unwrap();
+
+        let found = match entries.get(name) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.target.clone()),
+            Some(_) => {
+                entries.pop(name);
+                None
+            }
+            None => None,
+        };
+
+        match found {
+            Some(_) => self.0.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.0.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    /// Stores the result of looking up a link
+    pub fn insert(&self, name: &str, target: Option<String>) {
+        let Some(entries) = &self.0.entries else {
+            return;
+        };
+
+        let ttl = match target {
+            Some(_) => self.0.ttl,
+            None => self.0.negative_ttl,
+        };
+        entries.lock().unwrap().put(
+            name.to_string(),
+            Entry {
+                target,
+                expires: Instant::now() + ttl,
+            },
+        );
+    }
+
+    /// Forgets a link, which needs to be done whenever it is changed
+    pub fn invalidate(&self, name: &str) {
+        if let Some(entries) = &self.0.entries {
+            entries.lock().unwrap().pop(name);
+        }
+    }
+
+    /// Forgets every link, for when changes may have been missed
+    pub fn clear(&self) {
+        if let Some(entries) = &self.0.entries {
+            entries.lock().unwrap().clear();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.0.hits.load(Ordering::Relaxed);
+        let misses = self.0.misses.load(Ordering::Relaxed);
+        let (entries, capacity) = match &self.0.entries {
+            Some(entries) => {
+                let entries = entries.lock().unwrap();
+                (entries.len(), entries.cap().get())
+            }
+            None => (0, 0),
+        };
+
+        CacheStats {
+            hits,
+            misses,
+            hit_rate: match hits + misses {
+                0 => 0.0,
+                total => hits as f64 / total as f64,
+            },
+            entries,
+            capacity,
+        }
+    }
+}
+
+/// Sets up the cache from the configuration
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Redirect Cache", |rocket| async {
+        let config: AppConfig = rocket
+            .figment()
+            .extract()
+            .expect("Could not find App Config");
+
+        rocket.manage(RedirectCache::from_config(&config.redirect_cache))
+    })
+}
@@ -0,0 +1,97 @@
+//! Puts the URLs links redirect to in a canonical form, so that the same page
+//! written in different ways (such as with a different case host, a trailing
+//! slash or tracking parameters) can be recognised when it already has a link.
+//! The URL a link redirects to is never changed, the canonical form is only
+//! used to look for duplicates.
+
+use reqwest::Url;
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use rocket_db_pools::diesel::PgPool;
+use rocket_db_pools::Database;
+
+use crate::config::{AppConfig, UrlConfig};
+use crate::database::{Db, Url as Link};
+
+/// Returns whether a query parameter is one of the ones which are stripped,
+/// which are either exact names or prefixes ending with `*`
+fn is_stripped(config: &UrlConfig, param: &str) -> bool {
+    config
+        .strip_params
+        .iter()
+        .any(|p| match p.strip_suffix('*') {
+            Some(prefix) => param.starts_with(prefix),
+            None => param == p,
+        })
+}
+
+/// Returns the canonical form of a URL. The scheme and host are lower case,
+/// default ports are removed, trailing slashes are removed from the path,
+/// tracking parameters are stripped, and the rest of the query is sorted.
+/// URLs which can't be parsed are left as they are.
+pub fn canonicalize(config: &UrlConfig, url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url.trim()) else {
+        return url.to_string();
+    };
+
+    // Parsing already lower cases the scheme and host, and drops the port if
+    // it is the default for the scheme
+    if parsed.path().len() > 1 && parsed.path().ends_with('/') {
+        let path = parsed.path().trim_end_matches('/').to_string();
+        parsed.set_path(if path.is_empty() { "/" } else { &path });
+    }
+
+    let mut params: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(k, _)| !is_stripped(config, k))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    params.sort();
+
+    match params.is_empty() {
+        true => parsed.set_query(None),
+        false => {
+            parsed.query_pairs_mut().clear().extend_pairs(params);
+        }
+    }
+
+    parsed.to_string()
+}
+
+/// Brings the canonical URLs of every link up to date with the
+/// configuration, which could have changed since they were stored
+async fn update_all(pool: &PgPool, config: &UrlConfig) -> anyhow::Result<()> {
+    let mut conn = pool.get().await?;
+
+    let mut updated = 0;
+    for (name, url, old_canonical) in Link::get_all_urls(&mut conn).await? {
+        let canonical = canonicalize(config, &url);
+        if canonical != old_canonical {
+            Link::set_canonical_url(&mut conn, &name, &canonical).await?;
+            updated += 1;
+        }
+    }
+
+    if updated > 0 {
+        info!("Updated the canonical URLs of {} links", updated);
+    }
+    Ok(())
+}
+
+/// Updates the stored canonical URLs once the application has launched
+pub fn stage() -> AdHoc {
+    AdHoc::on_liftoff("Canonical URLs", |rocket| {
+        Box::pin(async move {
+            let (Some(db), Some(config)) = (Db::fetch(rocket), rocket.state::<AppConfig>()) else {
+                return;
+            };
+
+            let (pool, config) = ((**db).clone(), config.urls.clone());
+            tokio::spawn(async move {
+                if let Err(e) = update_all(&pool, &config).await {
+                    error!("Could not update the canonical URLs: {}", e);
+                }
+            });
+        })
+    })
+}
@@ -1,19 +1,393 @@
 //! Stores custom configuration information
 
+use std::collections::HashMap;
+
+use chrono::Duration;
 use figment::{
     providers::{Env, Format, Toml},
     Figment, Profile,
 };
-use serde::{Deserialize, Serialize};
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+/// Name given to the provider set with the `client_*` options, which
+/// configured providers can't use
+pub const DEFAULT_PROVIDER: &str = "default";
+
+/// How users log in to the application
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum AuthProvider {
+    /// Redirect to an OIDC server, configured with the `client_*` options
+    #[default]
+    Oidc,
+    /// Usernames and passwords stored in the database
+    Local,
+}
+
+/// Connection details for one of the OIDC servers users can log in with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OidcProviderConfig {
+    /// Short name used to identify the provider, e.g. in URLs
+    pub name: String,
+    /// Name shown to users when picking a provider, defaulting to `name`
+    #[serde(default)]
+    pub display_name: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+    pub client_url: String,
+    /// The claim in the ID token which lists the user's groups, used to sync
+    /// team membership
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+}
+
+impl OidcProviderConfig {
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Limits on how many links each user can create, limits which aren't set
+/// aren't enforced
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct QuotaConfig {
+    /// The most links a user can have created
+    #[serde(default)]
+    pub max_links: Option<i64>,
+    /// The most links which can use any one prefix rule
+    #[serde(default)]
+    pub max_links_per_prefix: Option<i64>,
+    /// The most links a user can create within `window`
+    #[serde(default)]
+    pub max_links_per_window: Option<i64>,
+    /// The length of the time window (in seconds)
+    #[serde(default = "default_quota_window")]
+    pub window: i64,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            max_links: None,
+            max_links_per_prefix: None,
+            max_links_per_window: None,
+            window: default_quota_window(),
+        }
+    }
+}
+
+impl QuotaConfig {
+    pub fn window(&self) -> Duration {
+        Duration::seconds(self.window)
+    }
+}
+
+/// The size of a token bucket used for rate limiting
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BucketConfig {
+    /// How many requests can be made at once before being limited
+    pub burst: u32,
+    /// How many requests can be made each minute once the burst is used up
+    pub per_minute: u32,
+}
+
+/// Rate limits for each group of routes, see [`crate::ratelimit`]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RateLimitConfig {
+    /// Proxies which are trusted to set `X-Forwarded-For`, as addresses or
+    /// CIDR ranges
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// The limits for each group of routes ("redirect", "api" and "login"),
+    /// groups which aren't listed aren't limited
+    #[serde(default = "default_rate_limit_groups")]
+    pub groups: HashMap<String, BucketConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            trusted_proxies: Vec::new(),
+            groups: default_rate_limit_groups(),
+        }
+    }
+}
+
+/// Bounds on the cache of where links redirect to
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CacheConfig {
+    /// The most links which are cached, zero turns the cache off
+    #[serde(default = "default_cache_capacity")]
+    pub capacity: usize,
+    /// How long (in seconds) a link is cached for
+    #[serde(default = "default_cache_ttl")]
+    pub ttl: u64,
+    /// How long (in seconds) a link which doesn't exist is cached for
+    #[serde(default = "default_cache_negative_ttl")]
+    pub negative_ttl: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: default_cache_capacity(),
+            ttl: default_cache_ttl(),
+            negative_ttl: default_cache_negative_ttl(),
+        }
+    }
+}
+
+/// How link names are compared, so that names which look the same can't be
+/// used for different links, see [`crate::names`]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NameConfig {
+    /// Whether names which only differ in case are the same link
+    #[serde(default = "default_true")]
+    pub case_insensitive: bool,
+    /// Whether names are compared in Unicode NFKC form, so that e.g. full
+    /// width letters are the same as the usual ones
+    #[serde(default = "default_true")]
+    pub nfkc: bool,
+    /// The Unicode scripts (such as "Latin" or "Cyrillic") names can be
+    /// written in, or "ASCII" for only ASCII characters. Names can use any
+    /// script if this is empty.
+    #[serde(default)]
+    pub scripts: Vec<String>,
+    /// Whether names which mix scripts, or could be mistaken for an existing
+    /// name, are rejected
+    #[serde(default = "default_true")]
+    pub reject_confusables: bool,
+}
+
+impl Default for NameConfig {
+    fn default() -> Self {
+        NameConfig {
+            case_insensitive: true,
+            nfkc: true,
+            scripts: Vec::new(),
+            reject_confusables: true,
+        }
+    }
+}
+
+/// How the URLs links redirect to are compared when looking for links which
+/// already exist (see [`crate::canonical`]), and what is fetched from them
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UrlConfig {
+    /// Query parameters which don't change the page, such as tracking
+    /// parameters, and are ignored. Names ending with `*` match any parameter
+    /// starting with the rest.
+    #[serde(default = "default_strip_params")]
+    pub strip_params: Vec<String>,
+    /// Whether links created without a title get the title of the page they
+    /// redirect to, see [`crate::titles`]
+    #[serde(default)]
+    pub fetch_titles: bool,
+}
+
+impl Default for UrlConfig {
+    fn default() -> Self {
+        UrlConfig {
+            strip_params: default_strip_params(),
+            fetch_titles: false,
+        }
+    }
+}
 
 /// Custom config options used throughout the application
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct AppConfig {
+    #[serde(default)]
+    pub auth_provider: AuthProvider,
+    #[serde(default)]
     pub client_id: String,
+    #[serde(default)]
     pub client_secret: String,
+    #[serde(default)]
     pub client_url: String,
+    /// The OIDC servers users can pick between, the `client_*` options can be
+    /// used instead when there is only one
+    #[serde(default, deserialize_with = "deserialize_providers")]
+    pub providers: Vec<OidcProviderConfig>,
     pub hostname: String,
+    /// How often (in seconds) the authentication server's metadata and keys
+    /// are refreshed
+    #[serde(default = "default_auth_refresh_interval")]
+    pub auth_refresh_interval: u64,
+    /// How long (in seconds) a session can go unused before it is expired
+    #[serde(default = "default_session_idle_timeout")]
+    pub session_idle_timeout: i64,
+    /// How long (in seconds) a session can last before the user has to log
+    /// in again, regardless of use
+    #[serde(default = "default_session_absolute_timeout")]
+    pub session_absolute_timeout: i64,
+    /// IDs of the users which are allowed to manage other users' sessions
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Administrators aren't limited by these
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub redirect_cache: CacheConfig,
+    /// How long (in seconds) the response to a request with an
+    /// `Idempotency-Key` is replayed for retries
+    #[serde(default = "default_idempotency_window")]
+    pub idempotency_window: i64,
+    #[serde(default)]
+    pub names: NameConfig,
+    /// Names which can't be used for links, on top of the ones which are
+    /// already used by the application's pages and files
+    #[serde(default)]
+    pub reserved_names: Vec<String>,
+    #[serde(default)]
+    pub urls: UrlConfig,
+    /// Whether logged in users can browse the links at "/directory"
+    #[serde(default)]
+    pub directory: bool,
+    /// Whether logged in users are shown the links with the closest names
+    /// when a link doesn't exist
+    #[serde(default = "default_true")]
+    pub suggestions: bool,
+}
+
+impl AppConfig {
+    /// Returns all the configured OIDC servers, including the one set by the
+    /// `client_*` options
+    pub fn oidc_providers(&self) -> Vec<OidcProviderConfig> {
+        let mut providers = self.providers.clone();
+
+        if !self.client_url.is_empty() {
+            providers.insert(
+                0,
+                OidcProviderConfig {
+                    name: DEFAULT_PROVIDER.to_string(),
+                    display_name: None,
+                    client_id: self.client_id.clone(),
+                    client_secret: self.client_secret.clone(),
+                    client_url: self.client_url.clone(),
+                    groups_claim: default_groups_claim(),
+                },
+            );
+        }
+
+        providers
+    }
+
+    pub fn session_idle_timeout(&self) -> Duration {
+        Duration::seconds(self.session_idle_timeout)
+    }
+
+    pub fn session_absolute_timeout(&self) -> Duration {
+        Duration::seconds(self.session_absolute_timeout)
+    }
+
+    pub fn idempotency_window(&self) -> Duration {
+        Duration::seconds(self.idempotency_window)
+    }
+
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        self.admins.iter().any(|x| x == user_id)
+    }
+}
+
+/// Refuses providers named after the one set by the `client_*` options, as
+/// sessions from the two couldn't be told apart
+fn deserialize_providers<'de, D>(deserializer: D) -> Result<Vec<OidcProviderConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let providers = Vec::<OidcProviderConfig>::deserialize(deserializer)?;
+
+    match providers.iter().find(|p| p.name == DEFAULT_PROVIDER) {
+        Some(_) => Err(de::Error::custom(format!(
+            "the provider name \"{}\" is reserved for the client_* options",
+            DEFAULT_PROVIDER
+        ))),
+        None => Ok(providers),
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_strip_params() -> Vec<String> {
+    ["utm_*", "fbclid", "gclid", "mc_cid", "mc_eid"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+
+fn default_quota_window() -> i64 {
+    60 * 60 * 24
+}
+
+fn default_cache_capacity() -> usize {
+    10_000
+}
+
+fn default_cache_ttl() -> u64 {
+    60 * 5
+}
+
+fn default_cache_negative_ttl() -> u64 {
+    30
+}
+
+fn default_rate_limit_groups() -> HashMap<String, BucketConfig> {
+    HashMap::from([
+        (
+            "redirect".to_string(),
+            BucketConfig {
+                burst: 60,
+                per_minute: 300,
+            },
+        ),
+        (
+            "api".to_string(),
+            BucketConfig {
+                burst: 20,
+                per_minute: 60,
+            },
+        ),
+        (
+            "login".to_string(),
+            BucketConfig {
+                burst: 5,
+                per_minute: 10,
+            },
+        ),
+    ])
+}
+
+fn default_idempotency_window() -> i64 {
+    60 * 60 * 24
+}
+
+fn default_auth_refresh_interval() -> u64 {
+    60 * 60
+}
+
+fn default_session_idle_timeout() -> i64 {
+    60 * 60 * 24
+}
+
+fn default_session_absolute_timeout() -> i64 {
+    60 * 60 * 24 * 30
 }
 
 pub fn get_figment() -> Figment {
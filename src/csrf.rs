@@ -0,0 +1,171 @@
+//! Protection against cross-site request forgery. Each session has a token,
+//! which pages include in their forms (as `csrf_token`) and scripts send in
+//! the `X-CSRF-Token` header, and requests which change anything have to
+//! include it if they are authenticated with the session cookie.
+//!
+//! Requests without a session aren't checked, as there is no user for them to
+//! act as, and are left to fail authentication if they need it.
+
+use std::ops::Deref;
+
+use hmac::{Hmac, Mac};
+use rocket::data::{self, Data, FromData};
+use rocket::form::{self, DataField, Form, FromForm, Options, ValueField};
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use sha2::Sha256;
+use utoipa::ToSchema;
+
+use crate::auth::User;
+
+/// The header scripts send the token in
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// The form field pages send the token in
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Returns the token for a session. This is derived from the session's ID,
+/// which is secret, so it doesn't need storing and changes whenever the user
+/// logs in again.
+pub fn token(session_id: &str) -> String {
+    hex::encode(mac(session_id).finalize().into_bytes())
+}
+
+fn mac(session_id: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(session_id.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(b"csrf");
+    mac
+}
+
+/// Checks a token against the session's, taking the same time however much
+/// of it matches
+pub fn verify(session_id: &str, token: &str) -> bool {
+    hex::decode(token).is_ok_and(|token| mac(session_id).verify_slice(&token).is_ok())
+}
+
+/// Returns whether the request may go ahead, given the token it was sent with
+async fn allowed(request: &Request<'_>, token: Option<&str>) -> bool {
+    // Requests which aren't made with the session cookie can't be forged, and
+    // are left to fail authentication if they need it
+    let Outcome::Success(user) = request.guard::<User>().await else {
+        return true;
+    };
+
+    let token = token.or_else(|| request.headers().get_one(CSRF_HEADER));
+    match token {
+        Some(token) if verify(&user.session, token) => true,
+        _ => {
+            warn!(
+                "Rejected a request from {} without a valid CSRF token",
+                user.id
+            );
+            false
+        }
+    }
+}
+
+/// Request guard which checks the token in the `X-CSRF-Token` header, failing
+/// with `403 Forbidden` if it is missing or wrong. Forms should use
+/// [`CsrfForm`] instead.
+pub struct Csrf;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Csrf {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match allowed(request, None).await {
+            true => Outcome::Success(Csrf),
+            false => Outcome::Error((Status::Forbidden, ())),
+        }
+    }
+}
+
+/// A form which has to include the token, either as the `csrf_token` field or
+/// in the `X-CSRF-Token` header. This fails with `403 Forbidden` if it is
+/// missing or wrong.
+pub struct CsrfForm<T>(T);
+
+impl<T> Deref for CsrfForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// A form, along with the token which was sent in it
+struct WithToken<T> {
+    token: Option<String>,
+    form: T,
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromForm<'r> for WithToken<T> {
+    type Context = (Option<String>, T::Context);
+
+    fn init(opts: Options) -> Self::Context {
+        (None, T::init(opts))
+    }
+
+    fn push_value(ctxt: &mut Self::Context, field: ValueField<'r>) {
+        match field.name == CSRF_FIELD {
+            true => ctxt.0 = Some(field.value.to_string()),
+            false => T::push_value(&mut ctxt.1, field),
+        }
+    }
+
+    async fn push_data(ctxt: &mut Self::Context, field: DataField<'r, '_>) {
+        T::push_data(&mut ctxt.1, field).await
+    }
+
+    fn push_error(ctxt: &mut Self::Context, error: form::Error<'r>) {
+        T::push_error(&mut ctxt.1, error)
+    }
+
+    fn finalize(ctxt: Self::Context) -> form::Result<'r, Self> {
+        Ok(WithToken {
+            token: ctxt.0,
+            form: T::finalize(ctxt.1)?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromForm<'r>> FromData<'r> for CsrfForm<T> {
+    type Error = Option<form::Errors<'r>>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let form = match Form::<WithToken<T>>::from_data(request, data).await {
+            Outcome::Success(form) => form.into_inner(),
+            Outcome::Error((status, errors)) => return Outcome::Error((status, Some(errors))),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+
+        match allowed(request, form.token.as_deref()).await {
+            true => Outcome::Success(CsrfForm(form.form)),
+            false => Outcome::Error((Status::Forbidden, None)),
+        }
+    }
+}
+
+/// For forms which have nothing in them but the token. Any other fields are
+/// ignored.
+#[derive(Debug, ToSchema)]
+pub struct NoFields;
+
+#[rocket::async_trait]
+impl<'r> FromForm<'r> for NoFields {
+    type Context = ();
+
+    fn init(_opts: Options) -> Self::Context {}
+
+    fn push_value(_ctxt: &mut Self::Context, _field: ValueField<'r>) {}
+
+    async fn push_data(_ctxt: &mut Self::Context, _field: DataField<'r, '_>) {}
+
+    fn finalize(_ctxt: Self::Context) -> form::Result<'r, Self> {
+        Ok(NoFields)
+    }
+}
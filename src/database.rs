@@ -1,12 +1,23 @@
 //! Stores the Database structures and functions which can be used for
 //! interacting with the database via diesel
 
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use rocket::fairing::AdHoc;
+use rocket::http::uri::fmt::{Formatter, Query, UriDisplay};
 use rocket::response::Debug;
 use rocket::serde::{Deserialize, Serialize};
-use rocket_db_pools::diesel::{self, prelude::*, PgPool};
+use rocket_db_pools::diesel::dsl::sql;
+use rocket_db_pools::diesel::sql_types::{Bool, Float, Text};
+use rocket_db_pools::diesel::{self, prelude::*, AsyncPgConnection, PgPool};
 use rocket_db_pools::{Connection, Database};
+use utoipa::ToSchema;
 
+use crate::rules::RuleKind;
 use crate::schema;
 
 pub type Result<T, E = Debug<diesel::result::Error>> = std::result::Result<T, E>;
@@ -21,60 +32,1232 @@ pub struct Db(PgPool);
 pub struct Url {
     pub name: String,
     pub url: String,
+    /// The user who created the link
+    pub user_id: Option<String>,
+    /// The team which owns the link, if any
+    pub team_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+    /// The form of the name the link is looked up by, see [`crate::names`]
+    #[serde(skip)]
+    pub normalized_name: String,
+    /// Names with the same skeleton look alike
+    #[serde(skip)]
+    pub skeleton: String,
+    /// The URL in the form used to look for duplicates, see
+    /// [`crate::canonical`]
+    #[serde(skip)]
+    pub canonical_url: String,
+    /// The link this is an alias of, which it redirects to the same place as
+    pub alias_of: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Lower case labels used to group links
+    pub tags: Vec<String>,
+    /// Whether the link is shown in the directory
+    pub listed: bool,
+}
+
+/// The order links are listed in when searching
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, FromFormField, Serialize, ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum LinkOrder {
+    /// The best matches first, or the newest links without a query
+    #[default]
+    Relevance,
+    Name,
+    /// Links without a title come last
+    Title,
+    Newest,
+}
+
+impl LinkOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkOrder::Relevance => "relevance",
+            LinkOrder::Name => "name",
+            LinkOrder::Title => "title",
+            LinkOrder::Newest => "newest",
+        }
+    }
+}
+
+impl UriDisplay<Query> for LinkOrder {
+    fn fmt(&self, f: &mut Formatter<'_, Query>) -> fmt::Result {
+        f.write_value(self.as_str())
+    }
+}
+
+rocket::http::impl_from_uri_param_identity!([Query] LinkOrder);
+
+/// What to look for when searching links
+pub struct LinkSearch<'a> {
+    pub query: Option<&'a str>,
+    /// Only links with all of these tags are returned
+    pub tags: Vec<String>,
+    pub order: LinkOrder,
+    /// Leaves out the links which aren't shown in the directory
+    pub listed_only: bool,
+    pub limit: i64,
+    pub offset: i64,
 }
 
 impl Url {
-    /// Returns whether a URL already exists in the database
-    pub async fn exists(conn: &mut Connection<Db>, name: &str) -> bool {
+    /// Returns whether a link with the given normalised name already exists
+    /// in the database
+    pub async fn exists(conn: &mut Connection<Db>, normalized: &str) -> bool {
         let res: Result<Url, _> = schema::urls::table
-            .filter(schema::urls::name.eq(name))
+            .filter(schema::urls::normalized_name.eq(normalized))
             .first(conn)
             .await;
 
         res.is_ok()
     }
 
-    /// Gets the row from the URL
-    pub async fn from_url(conn: &mut Connection<Db>, url: &str) -> Option<Url> {
-        let res: Result<Url, _> = schema::urls::table
+    /// Returns the name of a different link which looks like the given one
+    pub async fn confusable_with(
+        conn: &mut Connection<Db>,
+        normalized: &str,
+        skeleton: &str,
+    ) -> Option<String> {
+        schema::urls::table
+            .filter(schema::urls::skeleton.eq(skeleton))
+            .filter(schema::urls::normalized_name.ne(normalized))
+            .select(schema::urls::name)
+            .first(conn)
+            .await
+            .ok()
+    }
+
+    /// Returns the name, normalised name and skeleton of every link
+    pub async fn get_all_names(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<(String, String, String)>, diesel::result::Error> {
+        schema::urls::table
+            .select((
+                schema::urls::name,
+                schema::urls::normalized_name,
+                schema::urls::skeleton,
+            ))
+            .get_results(conn)
+            .await
+    }
+
+    /// Replaces the normalised name and skeleton of a link
+    pub async fn set_normalized(
+        conn: &mut AsyncPgConnection,
+        name: &str,
+        normalized: &str,
+        skeleton: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::update(schema::urls::table.filter(schema::urls::name.eq(name)))
+            .set((
+                schema::urls::normalized_name.eq(normalized),
+                schema::urls::skeleton.eq(skeleton),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns the link or alias with the given normalised name
+    pub async fn get(conn: &mut Connection<Db>, normalized: &str) -> Option<Url> {
+        schema::urls::table
+            .filter(schema::urls::normalized_name.eq(normalized))
+            .first(conn)
+            .await
+            .ok()
+    }
+
+    /// Returns the aliases of a link, sorted by name
+    pub async fn aliases(conn: &mut Connection<Db>, name: &str) -> Vec<Url> {
+        schema::urls::table
+            .filter(schema::urls::alias_of.eq(name))
+            .order(schema::urls::name.asc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Searches the links (but not aliases) by their names, titles, tags,
+    /// descriptions and URLs
+    pub async fn search(conn: &mut Connection<Db>, search: &LinkSearch<'_>) -> Vec<Url> {
+        let mut links = schema::urls::table
+            .filter(schema::urls::alias_of.is_null())
+            .into_boxed();
+
+        if !search.tags.is_empty() {
+            links = links.filter(schema::urls::tags.contains(&search.tags));
+        }
+        if search.listed_only {
+            links = links.filter(schema::urls::listed.eq(true));
+        }
+
+        // The search column is kept up to date by the database, so isn't in
+        // the schema
+        let query = search.query.filter(|q| !q.trim().is_empty());
+        if let Some(query) = query {
+            links = links.filter(
+                sql::<Bool>("search @@ websearch_to_tsquery('simple', ")
+                    .bind::<Text, _>(query.to_string())
+                    .sql(")"),
+            );
+        }
+
+        links = match (search.order, query) {
+            (LinkOrder::Relevance, Some(query)) => links.order((
+                sql::<Float>("ts_rank(search, websearch_to_tsquery('simple', ")
+                    .bind::<Text, _>(query.to_string())
+                    .sql("))")
+                    .desc(),
+                schema::urls::name.asc(),
+            )),
+            (LinkOrder::Relevance | LinkOrder::Newest, _) => {
+                links.order(schema::urls::created_at.desc())
+            }
+            (LinkOrder::Name, _) => links.order(schema::urls::normalized_name.asc()),
+            (LinkOrder::Title, _) => links.order((
+                schema::urls::title.asc().nulls_last(),
+                schema::urls::normalized_name.asc(),
+            )),
+        };
+
+        links
+            .limit(search.limit)
+            .offset(search.offset)
+            .get_results(conn)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Could not search links: {}", e);
+                Vec::new()
+            })
+    }
+
+    /// Returns the names of the listed links (and their aliases) which are
+    /// closest to the given normalised name, the closest first
+    pub async fn similar(conn: &mut Connection<Db>, normalized: &str, limit: i64) -> Vec<String> {
+        schema::urls::table
+            .filter(schema::urls::listed.eq(true))
+            .filter(sql::<Bool>("normalized_name % ").bind::<Text, _>(normalized))
+            .order((
+                sql::<Float>("similarity(normalized_name, ")
+                    .bind::<Text, _>(normalized)
+                    .sql(")")
+                    .desc(),
+                schema::urls::name.asc(),
+            ))
+            .select(schema::urls::name)
+            .limit(limit)
+            .get_results(conn)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Could not look for similar links: {}", e);
+                Vec::new()
+            })
+    }
+
+    /// Sets the title of a link if it doesn't have one, as long as it still
+    /// redirects to the same URL. The name can be one of the link's aliases.
+    pub async fn set_default_title(
+        conn: &mut AsyncPgConnection,
+        name: &str,
+        url: &str,
+        title: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        let alias_of: Option<String> = schema::urls::table
+            .filter(schema::urls::name.eq(name))
+            .select(schema::urls::alias_of)
+            .first(conn)
+            .await
+            .optional()?
+            .flatten();
+
+        diesel::update(schema::urls::table)
+            .filter(schema::urls::name.eq(alias_of.as_deref().unwrap_or(name)))
             .filter(schema::urls::url.eq(url))
+            .filter(schema::urls::title.is_null())
+            .set(schema::urls::title.eq(title))
+            .execute(conn)
+            .await
+    }
+
+    /// Returns the name of the link this is, or is an alias of
+    pub fn link_name(&self) -> &str {
+        self.alias_of.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Gets a link to the URL, given in its canonical form. Aliases aren't
+    /// returned, only the link they are an alias of.
+    pub async fn from_url(conn: &mut Connection<Db>, canonical: &str) -> Option<Url> {
+        let res: Result<Url, _> = schema::urls::table
+            .filter(schema::urls::canonical_url.eq(canonical))
+            .filter(schema::urls::alias_of.is_null())
             .first(conn)
             .await;
 
         res.ok()
     }
+
+    /// Returns the name, URL and canonical URL of every link
+    pub async fn get_all_urls(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<(String, String, String)>, diesel::result::Error> {
+        schema::urls::table
+            .select((
+                schema::urls::name,
+                schema::urls::url,
+                schema::urls::canonical_url,
+            ))
+            .get_results(conn)
+            .await
+    }
+
+    /// Replaces the canonical URL of a link
+    pub async fn set_canonical_url(
+        conn: &mut AsyncPgConnection,
+        name: &str,
+        canonical: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::update(schema::urls::table.filter(schema::urls::name.eq(name)))
+            .set(schema::urls::canonical_url.eq(canonical))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns whether the user is allowed to change an existing link, which
+    /// is when they created it or are in the team which owns it. Links from
+    /// before ownership was tracked can only be changed by administrators.
+    pub async fn user_can_edit(
+        conn: &mut Connection<Db>,
+        user_id: &str,
+        is_admin: bool,
+        normalized: &str,
+    ) -> bool {
+        let res: Result<Url, _> = schema::urls::table
+            .filter(schema::urls::normalized_name.eq(normalized))
+            .first(conn)
+            .await;
+
+        match res {
+            Ok(Url {
+                team_id: Some(team_id),
+                ..
+            }) => TeamMember::get(conn, team_id, user_id).await.is_some(),
+            Ok(Url {
+                user_id: Some(owner),
+                ..
+            }) => owner == user_id,
+            _ => is_admin,
+        }
+    }
 }
 
-#[derive(Deserialize, Insertable, Queryable, Serialize, Selectable)]
+/// A rule deciding which link names a user can create, see [`crate::rules`]
+/// for how they are matched
+#[derive(Clone, Deserialize, Insertable, Queryable, Serialize, Selectable)]
 #[diesel(table_name = crate::schema::prefixes)]
 #[serde(crate = "rocket::serde")]
 pub struct PrefixLink {
     pub user_id: String,
+    /// The prefix, glob or regular expression, depending on `kind`
     pub prefix: String,
+    pub kind: String,
+    /// Whether names matching this rule are blocked rather than allowed
+    pub deny: bool,
+    /// The longest name this rule allows
+    pub max_length: Option<i32>,
 }
 
 impl PrefixLink {
-    /// Returns all the prefixes which a given user is allowed to use
+    /// Returns all the rules which apply to a given user, either directly or
+    /// through the teams they are in
     pub async fn get_all(conn: &mut Connection<Db>, user_id: &str) -> Vec<PrefixLink> {
-        schema::prefixes::table
+        let mut prefixes: Vec<PrefixLink> = schema::prefixes::table
             .filter(schema::prefixes::user_id.eq(user_id))
             .get_results(conn)
             .await
+            .unwrap_or_default();
+
+        let team_prefixes: Vec<(String, String, bool, Option<i32>)> = schema::team_prefixes::table
+            .inner_join(
+                schema::team_members::table
+                    .on(schema::team_members::team_id.eq(schema::team_prefixes::team_id)),
+            )
+            .filter(schema::team_members::user_id.eq(user_id))
+            .select((
+                schema::team_prefixes::prefix,
+                schema::team_prefixes::kind,
+                schema::team_prefixes::deny,
+                schema::team_prefixes::max_length,
+            ))
+            .distinct()
+            .get_results(conn)
+            .await
+            .unwrap_or_default();
+
+        for (prefix, kind, deny, max_length) in team_prefixes {
+            prefixes.push(PrefixLink {
+                user_id: user_id.to_string(),
+                prefix,
+                kind,
+                deny,
+                max_length,
+            });
+        }
+
+        prefixes
+    }
+}
+
+/// A rule which applies to every member of a team
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::team_prefixes)]
+#[serde(crate = "rocket::serde")]
+pub struct TeamPrefix {
+    pub team_id: i32,
+    pub prefix: String,
+    pub kind: String,
+    pub deny: bool,
+    pub max_length: Option<i32>,
+}
+
+/// A group of users which can share prefixes and links, so they aren't
+/// orphaned when someone leaves
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::teams)]
+#[serde(crate = "rocket::serde")]
+pub struct Team {
+    pub id: i32,
+    pub name: String,
+    /// Members of this OIDC group are automatically added to the team
+    pub oidc_group: Option<String>,
+}
+
+impl Team {
+    /// Returns the team with the given ID
+    pub async fn get(conn: &mut Connection<Db>, id: i32) -> Option<Team> {
+        schema::teams::table
+            .filter(schema::teams::id.eq(id))
+            .first(conn)
+            .await
+            .ok()
+    }
+
+    /// Returns every team, sorted by name
+    pub async fn get_all(conn: &mut Connection<Db>) -> Vec<Team> {
+        schema::teams::table
+            .order(schema::teams::name.asc())
+            .get_results(conn)
+            .await
             .unwrap_or_default()
     }
 
-    /// Returns if a user is allowed to use a link with a given name
-    pub async fn user_can_link(conn: &mut Connection<Db>, user_id: &str, link_name: &str) -> bool {
-        let prefixes = PrefixLink::get_all(conn, user_id).await;
-        for p in prefixes {
-            let length = p.prefix.len();
+    /// Returns the teams a user is a member of
+    pub async fn get_for_user(conn: &mut Connection<Db>, user_id: &str) -> Vec<Team> {
+        schema::teams::table
+            .inner_join(schema::team_members::table)
+            .filter(schema::team_members::user_id.eq(user_id))
+            .select(Team::as_select())
+            .order(schema::teams::name.asc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
 
-            if length == 0 || (link_name.len() >= length && p.prefix == link_name[..length]) {
-                return true;
-            }
+    /// Creates a new team
+    pub async fn create(
+        conn: &mut Connection<Db>,
+        name: &str,
+        oidc_group: Option<&str>,
+    ) -> Result<i32, diesel::result::Error> {
+        diesel::insert_into(schema::teams::table)
+            .values((
+                schema::teams::name.eq(name),
+                schema::teams::oidc_group.eq(oidc_group),
+            ))
+            .returning(schema::teams::id)
+            .get_result(conn)
+            .await
+    }
+
+    /// Removes the team, any links it owned are left without a team
+    pub async fn delete(conn: &mut Connection<Db>, id: i32) -> Result<(), diesel::result::Error> {
+        diesel::delete(schema::teams::table.filter(schema::teams::id.eq(id)))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns the prefix rules which have been granted to the team
+    pub async fn prefixes(conn: &mut Connection<Db>, id: i32) -> Vec<TeamPrefix> {
+        schema::team_prefixes::table
+            .filter(schema::team_prefixes::team_id.eq(id))
+            .order(schema::team_prefixes::prefix.asc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Adds a prefix rule for members of the team, replacing any existing
+    /// rule with the same pattern
+    pub async fn add_prefix(
+        conn: &mut Connection<Db>,
+        id: i32,
+        prefix: &str,
+        kind: RuleKind,
+        deny: bool,
+        max_length: Option<i32>,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(schema::team_prefixes::table)
+            .values((
+                schema::team_prefixes::team_id.eq(id),
+                schema::team_prefixes::prefix.eq(prefix),
+                schema::team_prefixes::kind.eq(kind.as_str()),
+                schema::team_prefixes::deny.eq(deny),
+                schema::team_prefixes::max_length.eq(max_length),
+            ))
+            .on_conflict((
+                schema::team_prefixes::team_id,
+                schema::team_prefixes::prefix,
+            ))
+            .do_update()
+            .set((
+                schema::team_prefixes::kind.eq(kind.as_str()),
+                schema::team_prefixes::deny.eq(deny),
+                schema::team_prefixes::max_length.eq(max_length),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes a prefix rule from the team
+    pub async fn remove_prefix(
+        conn: &mut Connection<Db>,
+        id: i32,
+        prefix: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::delete(
+            schema::team_prefixes::table
+                .filter(schema::team_prefixes::team_id.eq(id))
+                .filter(schema::team_prefixes::prefix.eq(prefix)),
+        )
+        .execute(conn)
+        .await
+        .map(|_| ())
+    }
+
+    /// Makes the user's membership of teams linked to OIDC groups match the
+    /// groups the authentication server says they are in. Memberships which
+    /// were added in the application are left alone.
+    pub async fn sync_groups(
+        conn: &mut Connection<Db>,
+        user_id: &str,
+        groups: &[String],
+    ) -> Result<(), diesel::result::Error> {
+        let teams: Vec<i32> = schema::teams::table
+            .filter(schema::teams::oidc_group.eq_any(groups))
+            .select(schema::teams::id)
+            .get_results(conn)
+            .await?;
+
+        diesel::delete(
+            schema::team_members::table
+                .filter(schema::team_members::user_id.eq(user_id))
+                .filter(schema::team_members::synced.eq(true))
+                .filter(diesel::dsl::not(
+                    schema::team_members::team_id.eq_any(&teams),
+                )),
+        )
+        .execute(conn)
+        .await?;
+
+        for team_id in teams {
+            diesel::insert_into(schema::team_members::table)
+                .values(TeamMember {
+                    team_id,
+                    user_id: user_id.to_string(),
+                    is_admin: false,
+                    synced: true,
+                })
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A user's membership of a team
+#[derive(Insertable, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::team_members)]
+#[serde(crate = "rocket::serde")]
+pub struct TeamMember {
+    pub team_id: i32,
+    pub user_id: String,
+    /// Whether the user can manage the team's members
+    pub is_admin: bool,
+    /// Whether the membership comes from an OIDC group
+    pub synced: bool,
+}
+
+impl TeamMember {
+    /// Returns the user's membership of the team, if they are in it
+    pub async fn get(conn: &mut Connection<Db>, team_id: i32, user_id: &str) -> Option<TeamMember> {
+        schema::team_members::table
+            .filter(schema::team_members::team_id.eq(team_id))
+            .filter(schema::team_members::user_id.eq(user_id))
+            .first(conn)
+            .await
+            .ok()
+    }
+
+    /// Returns all the members of a team
+    pub async fn get_all(conn: &mut Connection<Db>, team_id: i32) -> Vec<TeamMember> {
+        schema::team_members::table
+            .filter(schema::team_members::team_id.eq(team_id))
+            .order(schema::team_members::user_id.asc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Adds the user to the team, or changes whether they are a team admin
+    /// if they are already in it
+    pub async fn set(
+        conn: &mut Connection<Db>,
+        team_id: i32,
+        user_id: &str,
+        is_admin: bool,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(schema::team_members::table)
+            .values(TeamMember {
+                team_id,
+                user_id: user_id.to_string(),
+                is_admin,
+                synced: false,
+            })
+            .on_conflict((schema::team_members::team_id, schema::team_members::user_id))
+            .do_update()
+            .set((
+                schema::team_members::is_admin.eq(is_admin),
+                schema::team_members::synced.eq(false),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes the user from the team
+    pub async fn remove(
+        conn: &mut Connection<Db>,
+        team_id: i32,
+        user_id: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::delete(
+            schema::team_members::table
+                .filter(schema::team_members::team_id.eq(team_id))
+                .filter(schema::team_members::user_id.eq(user_id)),
+        )
+        .execute(conn)
+        .await
+        .map(|_| ())
+    }
+}
+
+/// How often a session's `last_seen` is updated while it is being used
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+/// A server side login session, the ID of which is stored in the user's
+/// cookie so that it can be expired or revoked
+#[derive(Insertable, Queryable, Selectable)]
+#[diesel(table_name = crate::schema::sessions)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    /// Token used to renew the session with the authentication server
+    pub refresh_token: Option<String>,
+    /// When the session should next be renewed with the authentication server
+    pub expires_at: Option<NaiveDateTime>,
+    /// The ID token given when logging in, used as a hint when logging out of
+    /// the authentication server
+    pub id_token: Option<String>,
+    /// The authentication server's ID for the session, used for back-channel
+    /// logout
+    pub sid: Option<String>,
+    /// Name of the OIDC provider the user logged in with
+    pub provider: Option<String>,
+}
+
+impl Session {
+    /// Creates a new session with a random ID for the given user, this still
+    /// needs to be inserted with [`Session::create`]
+    pub fn new(
+        user_id: String,
+        refresh_token: Option<String>,
+        expires_in: Option<Duration>,
+    ) -> Self {
+        let id = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(48)
+            .map(char::from)
+            .collect();
+        let now = Utc::now().naive_utc();
+
+        Session {
+            id,
+            user_id,
+            created_at: now,
+            last_seen: now,
+            refresh_token,
+            expires_at: expires_in.map(|d| now + d),
+            id_token: None,
+            sid: None,
+            provider: None,
         }
+    }
+
+    /// Stores which OIDC provider the session was created with
+    pub fn with_provider(mut self, provider: String) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Stores the ID token and the authentication server's session ID
+    pub fn with_id_token(mut self, id_token: String, sid: Option<String>) -> Self {
+        self.id_token = Some(id_token);
+        self.sid = sid;
+        self
+    }
+
+    /// Adds the session to the database
+    pub async fn create(&self, conn: &mut Connection<Db>) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(schema::sessions::table)
+            .values(self)
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns the session with the given ID
+    pub async fn get(conn: &mut Connection<Db>, id: &str) -> Option<Session> {
+        schema::sessions::table
+            .filter(schema::sessions::id.eq(id))
+            .first(conn)
+            .await
+            .ok()
+    }
+
+    /// Returns every session in the database, most recently used first
+    pub async fn get_all(conn: &mut Connection<Db>) -> Vec<Session> {
+        schema::sessions::table
+            .order(schema::sessions::last_seen.desc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Returns whether the session has not been used for longer than `idle`
+    /// or has existed for longer than `absolute`
+    pub fn has_expired(&self, idle: Duration, absolute: Duration) -> bool {
+        let now = Utc::now().naive_utc();
+        now - self.last_seen > idle || now - self.created_at > absolute
+    }
+
+    /// Returns whether the session needs to be renewed with the
+    /// authentication server
+    pub fn needs_refresh(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expiry| expiry <= Utc::now().naive_utc())
+    }
 
-        false
+    /// Returns whether the session was last marked as used long enough ago to
+    /// need marking again, so that it isn't written to on every request
+    pub fn needs_touch(&self) -> bool {
+        Utc::now().naive_utc() - self.last_seen > Duration::seconds(TOUCH_INTERVAL_SECS)
+    }
+
+    /// Marks the session as being used now
+    pub async fn touch(conn: &mut Connection<Db>, id: &str) -> Result<(), diesel::result::Error> {
+        diesel::update(schema::sessions::table)
+            .filter(schema::sessions::id.eq(id))
+            .set(schema::sessions::last_seen.eq(Utc::now().naive_utc()))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Stores the new tokens after the session has been renewed
+    pub async fn update_tokens(
+        conn: &mut Connection<Db>,
+        id: &str,
+        refresh_token: Option<String>,
+        expires_in: Option<Duration>,
+    ) -> Result<(), diesel::result::Error> {
+        let expires_at = expires_in.map(|d| Utc::now().naive_utc() + d);
+
+        diesel::update(schema::sessions::table)
+            .filter(schema::sessions::id.eq(id))
+            .set((
+                schema::sessions::refresh_token.eq(refresh_token),
+                schema::sessions::expires_at.eq(expires_at),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes a single session, logging it out
+    pub async fn delete(conn: &mut Connection<Db>, id: &str) -> Result<(), diesel::result::Error> {
+        diesel::delete(schema::sessions::table.filter(schema::sessions::id.eq(id)))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes every session for a user, logging them out everywhere
+    pub async fn delete_all(
+        conn: &mut Connection<Db>,
+        user_id: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(schema::sessions::table.filter(schema::sessions::user_id.eq(user_id)))
+            .execute(conn)
+            .await
+    }
+
+    /// Removes the sessions with the given ID from an authentication server,
+    /// which is only unique for that server
+    pub async fn delete_by_sid(
+        conn: &mut Connection<Db>,
+        provider: &str,
+        sid: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(
+            schema::sessions::table
+                .filter(schema::sessions::provider.eq(provider))
+                .filter(schema::sessions::sid.eq(sid)),
+        )
+        .execute(conn)
+        .await
+    }
+
+    /// Removes all sessions which have timed out
+    pub async fn delete_expired(
+        conn: &mut Connection<Db>,
+        idle: Duration,
+        absolute: Duration,
+    ) -> Result<usize, diesel::result::Error> {
+        let now = Utc::now().naive_utc();
+
+        diesel::delete(
+            schema::sessions::table.filter(
+                schema::sessions::last_seen
+                    .lt(now - idle)
+                    .or(schema::sessions::created_at.lt(now - absolute)),
+            ),
+        )
+        .execute(conn)
+        .await
+    }
+}
+
+/// The name a user is shown by to other users, taken from the authentication
+/// server when they log in
+pub struct DisplayName;
+
+impl DisplayName {
+    /// Stores the name for a user, replacing the one from their last login
+    pub async fn set(
+        conn: &mut Connection<Db>,
+        user_id: &str,
+        display_name: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(schema::display_names::table)
+            .values((
+                schema::display_names::user_id.eq(user_id),
+                schema::display_names::display_name.eq(display_name),
+            ))
+            .on_conflict(schema::display_names::user_id)
+            .do_update()
+            .set((
+                schema::display_names::display_name.eq(display_name),
+                schema::display_names::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns the names of the given users, by their IDs. Users who haven't
+    /// logged in since names were stored are left out.
+    pub async fn get_all(conn: &mut Connection<Db>, user_ids: &[&str]) -> HashMap<String, String> {
+        schema::display_names::table
+            .filter(schema::display_names::user_id.eq_any(user_ids))
+            .select((
+                schema::display_names::user_id,
+                schema::display_names::display_name,
+            ))
+            .load::<(String, String)>(conn)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// How many times each name has been followed
+pub struct LinkHits;
+
+impl LinkHits {
+    /// Adds to the count of a name, given in its normalized form. Names which
+    /// have been deleted since they were followed are skipped.
+    pub async fn add(
+        conn: &mut AsyncPgConnection,
+        normalized_name: &str,
+        hits: i64,
+    ) -> Result<(), diesel::result::Error> {
+        let name: Option<String> = schema::urls::table
+            .filter(schema::urls::normalized_name.eq(normalized_name))
+            .select(schema::urls::name)
+            .first(conn)
+            .await
+            .optional()?;
+        let Some(name) = name else {
+            return Ok(());
+        };
+
+        diesel::insert_into(schema::link_hits::table)
+            .values((
+                schema::link_hits::name.eq(&name),
+                schema::link_hits::hits.eq(hits),
+            ))
+            .on_conflict(schema::link_hits::name)
+            .do_update()
+            .set((
+                schema::link_hits::hits.eq(schema::link_hits::hits + hits),
+                schema::link_hits::last_hit_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns how many times each of the given names has been followed.
+    /// Names which never have been are left out.
+    pub async fn get_all(conn: &mut Connection<Db>, names: &[&str]) -> HashMap<String, i64> {
+        schema::link_hits::table
+            .filter(schema::link_hits::name.eq_any(names))
+            .select((schema::link_hits::name, schema::link_hits::hits))
+            .load::<(String, i64)>(conn)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// The ID of a back-channel logout token which has been used
+pub struct UsedLogoutToken;
+
+impl UsedLogoutToken {
+    /// Records that a logout token has been used, returning `false` if it
+    /// already had been. The ID is kept until `expires_at`, after which the
+    /// token is refused for its age, and older IDs are removed.
+    pub async fn record(
+        conn: &mut Connection<Db>,
+        issuer: &str,
+        jti: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<bool, diesel::result::Error> {
+        diesel::delete(schema::logout_tokens::table)
+            .filter(schema::logout_tokens::expires_at.lt(Utc::now().naive_utc()))
+            .execute(conn)
+            .await?;
+
+        diesel::insert_into(schema::logout_tokens::table)
+            .values((
+                schema::logout_tokens::issuer.eq(issuer),
+                schema::logout_tokens::jti.eq(jti),
+                schema::logout_tokens::expires_at.eq(expires_at),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await
+            .map(|inserted| inserted == 1)
+    }
+}
+
+/// An account for logging in with a username and password, used instead of
+/// an OIDC server when the local authentication provider is configured.
+///
+/// These functions take a plain connection so they can also be used from the
+/// command line, without a running server.
+#[derive(Insertable, Queryable, Selectable)]
+#[diesel(table_name = crate::schema::local_users)]
+pub struct LocalUser {
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl LocalUser {
+    /// Returns the user with the given username
+    pub async fn get(conn: &mut AsyncPgConnection, username: &str) -> Option<LocalUser> {
+        schema::local_users::table
+            .filter(schema::local_users::username.eq(username))
+            .first(conn)
+            .await
+            .ok()
+    }
+
+    /// Returns every local user, sorted by username
+    pub async fn get_all(conn: &mut AsyncPgConnection) -> Vec<LocalUser> {
+        schema::local_users::table
+            .order(schema::local_users::username.asc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Creates the user, or if they already exist, changes their password
+    pub async fn set_password(
+        conn: &mut AsyncPgConnection,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(schema::local_users::table)
+            .values(LocalUser {
+                username: username.to_string(),
+                password_hash: password_hash.to_string(),
+                created_at: Utc::now().naive_utc(),
+            })
+            .on_conflict(schema::local_users::username)
+            .do_update()
+            .set(schema::local_users::password_hash.eq(password_hash))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes the user, so they can no longer log in
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        username: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::delete(
+            schema::local_users::table.filter(schema::local_users::username.eq(username)),
+        )
+        .execute(conn)
+        .await
+        .map(|_| ())
+    }
+}
+
+/// An endpoint which is sent link events, see [`crate::webhooks`]. These are
+/// also used by the delivery worker, so take a plain connection.
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::webhooks)]
+#[serde(crate = "rocket::serde")]
+pub struct Webhook {
+    pub id: i32,
+    /// The user who registered the webhook
+    pub user_id: String,
+    pub url: String,
+    /// Used to sign deliveries
+    pub secret: String,
+    /// The events which are sent, or all of them if empty
+    pub events: Vec<String>,
+    /// Whether the webhook is sent events for every link, not just the ones
+    /// owned by the user or their teams
+    pub all_links: bool,
+    pub created_at: NaiveDateTime,
+}
+
+impl Webhook {
+    pub async fn get(conn: &mut AsyncPgConnection, id: i32) -> Option<Webhook> {
+        schema::webhooks::table
+            .filter(schema::webhooks::id.eq(id))
+            .first(conn)
+            .await
+            .ok()
+    }
+
+    /// Returns every webhook, oldest first
+    pub async fn get_all(conn: &mut AsyncPgConnection) -> Vec<Webhook> {
+        schema::webhooks::table
+            .order(schema::webhooks::id.asc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Returns the webhooks the user has registered
+    pub async fn get_for_user(conn: &mut AsyncPgConnection, user_id: &str) -> Vec<Webhook> {
+        schema::webhooks::table
+            .filter(schema::webhooks::user_id.eq(user_id))
+            .order(schema::webhooks::id.asc())
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Registers a new webhook with a random secret, returning its ID
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        user_id: &str,
+        url: &str,
+        events: &[String],
+        all_links: bool,
+    ) -> Result<i32, diesel::result::Error> {
+        let secret: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect();
+
+        diesel::insert_into(schema::webhooks::table)
+            .values((
+                schema::webhooks::user_id.eq(user_id),
+                schema::webhooks::url.eq(url),
+                schema::webhooks::secret.eq(secret),
+                schema::webhooks::events.eq(events),
+                schema::webhooks::all_links.eq(all_links),
+            ))
+            .returning(schema::webhooks::id)
+            .get_result(conn)
+            .await
+    }
+
+    /// Removes the webhook along with its deliveries
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        id: i32,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::delete(schema::webhooks::table.filter(schema::webhooks::id.eq(id)))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+}
+
+/// An event waiting to be sent to a webhook, or which has been sent
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i32,
+    pub event: String,
+    /// The JSON body which is sent
+    pub payload: String,
+    pub created_at: NaiveDateTime,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+    /// Whether we have given up trying to send it
+    pub failed: bool,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+}
+
+impl WebhookDelivery {
+    /// Adds an event to the outbox, this should be done in the same
+    /// transaction as the change which caused it
+    pub async fn enqueue(
+        conn: &mut AsyncPgConnection,
+        webhook_id: i32,
+        event: &str,
+        payload: &str,
+    ) -> Result<(), diesel::result::Error> {
+        diesel::insert_into(schema::webhook_deliveries::table)
+            .values((
+                schema::webhook_deliveries::webhook_id.eq(webhook_id),
+                schema::webhook_deliveries::event.eq(event),
+                schema::webhook_deliveries::payload.eq(payload),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns the most recent deliveries for a webhook
+    pub async fn get_recent(
+        conn: &mut AsyncPgConnection,
+        webhook_id: i32,
+        limit: i64,
+    ) -> Vec<WebhookDelivery> {
+        schema::webhook_deliveries::table
+            .filter(schema::webhook_deliveries::webhook_id.eq(webhook_id))
+            .order(schema::webhook_deliveries::id.desc())
+            .limit(limit)
+            .get_results(conn)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Takes deliveries which are due to be sent, pushing back their next
+    /// attempt by `lease` so that no other instance sends them at the same
+    /// time
+    pub async fn claim_due(
+        conn: &mut AsyncPgConnection,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<WebhookDelivery>, diesel::result::Error> {
+        use schema::webhook_deliveries::dsl;
+
+        conn.transaction(|conn| {
+            Box::pin(async move {
+                let now = Utc::now().naive_utc();
+                let ids: Vec<i64> = dsl::webhook_deliveries
+                    .filter(dsl::delivered_at.is_null())
+                    .filter(dsl::failed.eq(false))
+                    .filter(dsl::next_attempt_at.le(now))
+                    .order(dsl::next_attempt_at.asc())
+                    .limit(limit)
+                    .select(dsl::id)
+                    .for_update()
+                    .skip_locked()
+                    .get_results(conn)
+                    .await?;
+
+                diesel::update(dsl::webhook_deliveries.filter(dsl::id.eq_any(ids)))
+                    .set(dsl::next_attempt_at.eq(now + lease))
+                    .get_results(conn)
+                    .await
+            })
+        })
+        .await
+    }
+
+    /// Records an attempt to send the delivery. It is retried at
+    /// `next_attempt_at` if it wasn't sent, or marked as failed if that is
+    /// `None`.
+    pub async fn record_attempt(
+        conn: &mut AsyncPgConnection,
+        id: i64,
+        delivered: bool,
+        status: Option<i32>,
+        error: Option<&str>,
+        next_attempt_at: Option<NaiveDateTime>,
+    ) -> Result<(), diesel::result::Error> {
+        use schema::webhook_deliveries::dsl;
+
+        let now = Utc::now().naive_utc();
+        diesel::update(dsl::webhook_deliveries.filter(dsl::id.eq(id)))
+            .set((
+                dsl::attempts.eq(dsl::attempts + 1),
+                dsl::delivered_at.eq(delivered.then_some(now)),
+                dsl::failed.eq(!delivered && next_attempt_at.is_none()),
+                dsl::next_attempt_at.eq(next_attempt_at.unwrap_or(now)),
+                dsl::last_status.eq(status),
+                dsl::last_error.eq(error),
+            ))
+            .execute(conn)
+            .await
+            .map(|_| ())
     }
 }
 
@@ -0,0 +1,115 @@
+//! A page listing the links, so people can browse and search the names which
+//! are available. This is turned off unless `directory` is set, and links can
+//! opt out of being listed.
+
+use std::collections::HashMap;
+
+use rocket::fairing::AdHoc;
+use rocket::http::uri::Origin;
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::{context, Template};
+
+use crate::api::{self, API_LOCAL};
+use crate::auth::{self, User};
+use crate::config::AppConfig;
+use crate::database::{Db, DisplayName, LinkOrder, LinkSearch, Team, Url};
+use crate::utils::random_colour;
+
+/// How many links are shown on each page
+const PAGE_SIZE: i64 = 50;
+/// The last page which can be asked for, so that its offset (and the number
+/// of the page after it) doesn't overflow
+const MAX_PAGE: i64 = i64::MAX / PAGE_SIZE;
+
+/// Lists the links which are listed, along with who owns them
+#[get("/directory?<q>&<tag>&<sort>&<page>")]
+async fn directory(
+    config: &State<AppConfig>,
+    _user: User,
+    mut db: Connection<Db>,
+    q: Option<&str>,
+    tag: Option<&str>,
+    sort: Option<LinkOrder>,
+    page: Option<i64>,
+) -> Template {
+    let page = page.unwrap_or(1).clamp(1, MAX_PAGE);
+    let search = LinkSearch {
+        query: q,
+        tags: api::normalize_tags(tag.as_slice()),
+        order: sort.unwrap_or_default(),
+        listed_only: true,
+        // One more than is shown, to know if there is another page
+        limit: PAGE_SIZE + 1,
+        offset: (page - 1) * PAGE_SIZE,
+    };
+    let mut links = Url::search(&mut db, &search).await;
+    let has_next = links.len() as i64 > PAGE_SIZE;
+    links.truncate(PAGE_SIZE as usize);
+
+    let teams: HashMap<i32, String> = Team::get_all(&mut db)
+        .await
+        .into_iter()
+        .map(|t| (t.id, t.name))
+        .collect();
+    // User IDs from OIDC servers aren't meant to be shown, so owners without
+    // a stored name are left blank
+    let user_ids: Vec<&str> = links.iter().filter_map(|l| l.user_id.as_deref()).collect();
+    let names = DisplayName::get_all(&mut db, &user_ids).await;
+    let links: Vec<_> = links
+        .into_iter()
+        .map(|link| {
+            let owner = match link.team_id.and_then(|id| teams.get(&id)) {
+                Some(team) => Some(team.clone()),
+                None => link.user_id.and_then(|id| names.get(&id).cloned()),
+            };
+
+            context! {
+                short_url: config.hostname.clone() + &link.name,
+                name: link.name,
+                url: link.url,
+                title: link.title,
+                description: link.description,
+                tags: link.tags,
+                owner: owner,
+            }
+        })
+        .collect();
+
+    let previous = (page > 1).then(|| uri!(directory(q, tag, sort, Some(page - 1))).to_string());
+    let next = has_next.then(|| uri!(directory(q, tag, sort, Some(page + 1))).to_string());
+
+    Template::render(
+        "directory",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            links: links,
+            q: q,
+            tag: tag,
+            sort: sort.unwrap_or_default().as_str(),
+            previous: previous,
+            next: next,
+            name: "Directory",
+        },
+    )
+}
+
+/// Sends people who aren't logged in to the login page, otherwise the
+/// directory would be looked up as a link
+#[get("/directory", rank = 2)]
+fn login_first(uri: &Origin<'_>) -> Redirect {
+    Redirect::to(uri!(auth::login_page(_, Some(uri.to_string()))))
+}
+
+/// Serves the directory, if it is turned on
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Link Directory", |rocket| async {
+        let enabled = rocket.state::<AppConfig>().is_some_and(|c| c.directory);
+        match enabled {
+            true => rocket.mount("/", routes![directory, login_first]),
+            false => rocket,
+        }
+    })
+}
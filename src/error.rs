@@ -0,0 +1,236 @@
+//! The errors returned by the application. They are sent to API clients as
+//! RFC 7807 `application/problem+json`, and to browsers as a page, depending on
+//! what the request's `Accept` header prefers.
+
+use std::io::Cursor;
+
+use rocket::http::{ContentType, MediaType, Status};
+use rocket::request::Request;
+use rocket::response::{self, status, Responder, Response};
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket_dyn_templates::{context, Template};
+use utoipa::ToSchema;
+use validator::{ValidationErrors, ValidationErrorsKind};
+
+use crate::api::API_LOCAL;
+use crate::utils::random_colour;
+
+/// A field in the request which is invalid, so it can be highlighted in the
+/// form it came from
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    /// The field which is invalid
+    pub name: String,
+    pub description: String,
+}
+
+/// The body of an error response sent to API clients, as described in
+/// RFC 7807
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Problem {
+    /// Always "about:blank", as the status code says what went wrong
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    status: u16,
+    /// What went wrong, in a way which can be shown to the user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    /// The fields in the request which are invalid
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+    /// Whether the request would succeed if it was made again with `force`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    allow_force: bool,
+}
+
+/// An error which can be returned from any route
+#[derive(Debug)]
+pub struct AppError {
+    status: Status,
+    title: Option<String>,
+    detail: Option<String>,
+    errors: Vec<FieldError>,
+    allow_force: bool,
+    template: Option<&'static str>,
+    /// Anything else the page shows
+    page_data: Option<json::Value>,
+}
+
+impl AppError {
+    pub fn new(status: Status, detail: impl Into<String>) -> Self {
+        AppError {
+            detail: Some(detail.into()),
+            ..AppError::from(status)
+        }
+    }
+
+    /// Something went wrong on our side, which should have been logged
+    /// already
+    pub fn internal(detail: impl Into<String>) -> Self {
+        AppError::new(Status::InternalServerError, detail)
+    }
+
+    /// The request had fields which weren't valid
+    pub fn validation(errors: &ValidationErrors) -> Self {
+        let errors = errors
+            .errors()
+            .iter()
+            .filter_map(|(name, kind)| match kind {
+                ValidationErrorsKind::Field(errors) => Some(FieldError {
+                    name: name.to_string(),
+                    description: errors
+                        .iter()
+                        .map(|e| match &e.message {
+                            Some(message) => message.to_string(),
+                            None => e.code.to_string(),
+                        })
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        AppError {
+            errors,
+            ..AppError::new(Status::UnprocessableEntity, "Invalid request")
+        }
+    }
+
+    /// Replaces the title, which is otherwise the status's reason phrase
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Tells the client it can make the request again with `force`
+    pub fn allow_force(mut self) -> Self {
+        self.allow_force = true;
+        self
+    }
+
+    /// Shows browsers a different page to the one for the status
+    pub fn with_template(mut self, template: &'static str) -> Self {
+        self.template = Some(template);
+        self
+    }
+
+    /// Gives the page shown to browsers more to show, as `data`
+    pub fn with_page_data(mut self, data: json::Value) -> Self {
+        self.page_data = Some(data);
+        self
+    }
+
+    fn title(&self) -> String {
+        match &self.title {
+            Some(title) => title.clone(),
+            None => self.status.reason_lossy().to_string(),
+        }
+    }
+
+    /// Returns the body sent to API clients
+    pub fn problem(&self) -> Problem {
+        Problem {
+            kind: "about:blank",
+            title: self.title(),
+            status: self.status.code,
+            detail: self.detail.clone(),
+            errors: self.errors.clone(),
+            allow_force: self.allow_force,
+        }
+    }
+
+    /// Returns the template to show browsers, and the name of the page
+    fn page(&self) -> (&'static str, String) {
+        let (template, name) = match self.status.code {
+            404 => ("error/404", "Lost and Found"),
+            429 => ("error/429", "Slow Down"),
+            500 => ("error/500", "Oops"),
+            503 => ("error/503", "Auth Unavailable"),
+            _ => ("error/default", ""),
+        };
+
+        match (self.template, name) {
+            (Some(template), _) => (template, self.title()),
+            (None, "") => (template, self.title()),
+            (None, name) => (template, name.to_string()),
+        }
+    }
+}
+
+impl From<Status> for AppError {
+    fn from(status: Status) -> Self {
+        AppError {
+            status,
+            title: None,
+            detail: None,
+            errors: Vec::new(),
+            allow_force: false,
+            template: None,
+            page_data: None,
+        }
+    }
+}
+
+/// Returns whether the client would rather have JSON than a page. Clients
+/// which will take anything get JSON from the API and pages from everywhere
+/// else.
+pub fn prefers_json(request: &Request<'_>) -> bool {
+    let is_api = || request.uri().path().starts_with(API_LOCAL);
+
+    match request.accept() {
+        Some(accept) => {
+            let media = accept.preferred().media_type();
+            match (media.top().as_str(), media.sub().as_str()) {
+                (_, "json") => true,
+                (_, sub) if sub.ends_with("+json") => true,
+                ("*", "*") | ("application", "*") => is_api(),
+                _ => false,
+            }
+        }
+        None => is_api(),
+    }
+}
+
+impl<'r> Responder<'r, 'static> for AppError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if prefers_json(request) {
+            let body = json::to_string(&self.problem()).map_err(|e| {
+                error!("Could not serialise the error: {}", e);
+                Status::InternalServerError
+            })?;
+
+            return Response::build()
+                .status(self.status)
+                .header(ContentType(MediaType::new("application", "problem+json")))
+                .sized_body(body.len(), Cursor::new(body))
+                .ok();
+        }
+
+        let (template, name) = self.page();
+        let page = Template::render(
+            template,
+            context! {
+                colour: random_colour(),
+                name,
+                status: self.status.code,
+                title: self.title(),
+                detail: &self.detail,
+                errors: &self.errors,
+                data: &self.page_data,
+            },
+        );
+
+        status::Custom(self.status, page).respond_to(request)
+    }
+}
+
+/// Turns any error which doesn't have a route handling it (such as a request
+/// guard failing) into an [`AppError`]
+#[catch(default)]
+pub fn catch_all(status: Status, _request: &Request<'_>) -> AppError {
+    AppError::from(status)
+}
@@ -0,0 +1,96 @@
+//! Counts how many times links are followed. Counts are kept in memory and
+//! added to the database every so often, so following a link doesn't wait for
+//! a write. Each name is counted on its own, and a link's total is what its
+//! name and all of its aliases add up to.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, time};
+use rocket_db_pools::diesel::PgPool;
+use rocket_db_pools::Database;
+
+use crate::database::{Db, LinkHits};
+
+/// How often the counts are saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The counts which haven't been saved yet, by normalized name. This is a
+/// handle which can be cloned, so they can be saved from a background task.
+#[derive(Clone, Default)]
+pub struct HitCounter(Arc<Mutex<HashMap<String, i64>>>);
+
+impl HitCounter {
+    /// Counts a link being followed by one of its names
+    pub fn record(&self, normalized_name: &str) {
+        let mut counts = self.0.lock().unwrap();
+        *counts.entry(normalized_name.to_string()).or_default() += 1;
+    }
+
+    /// Returns the counts since they were last taken, starting again from
+    /// nothing
+    pub fn take(&self) -> HashMap<String, i64> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    /// Adds the counts to the database. Counts which can't be saved are lost,
+    /// rather than growing without bound while the database is down.
+    async fn save(&self, pool: &PgPool) {
+        let counts = self.take();
+        if counts.is_empty() {
+            return;
+        }
+
+        let mut conn = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Could not save how often links were followed: {}", e);
+                return;
+            }
+        };
+        for (name, hits) in counts {
+            if let Err(e) = LinkHits::add(&mut conn, &name, hits).await {
+                warn!("Could not save how often {} was followed: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Saves the counts every [`SAVE_INTERVAL`]
+async fn run(pool: PgPool, counter: HitCounter) {
+    let mut interval = time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        counter.save(&pool).await;
+    }
+}
+
+/// Starts counting how often links are followed, saving what is left when the
+/// application shuts down
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Link Hits", |rocket| async {
+        rocket
+            .manage(HitCounter::default())
+            .attach(AdHoc::on_liftoff("Link Hit Saver", |rocket| {
+                Box::pin(async move {
+                    if let (Some(db), Some(counter)) =
+                        (Db::fetch(rocket), rocket.state::<HitCounter>())
+                    {
+                        tokio::spawn(run((**db).clone(), counter.clone()));
+                    }
+                })
+            }))
+            .attach(AdHoc::on_shutdown("Link Hit Saver", |rocket| {
+                Box::pin(async move {
+                    if let (Some(db), Some(counter)) =
+                        (Db::fetch(rocket), rocket.state::<HitCounter>())
+                    {
+                        counter.save(db).await;
+                    }
+                })
+            }))
+    })
+}
@@ -0,0 +1,123 @@
+//! Support for the `Idempotency-Key` header, so clients can safely retry
+//! requests which change things. The first response to a request with a key
+//! is stored, and retries with the same key within the window get that
+//! response back instead of making the change again.
+
+use chrono::{Duration, Utc};
+use diesel::sql_types::Text;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket_db_pools::diesel::prelude::*;
+use rocket_db_pools::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::database::Db;
+use crate::schema;
+
+/// The longest key which is accepted
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Request guard for the optional `Idempotency-Key` header, which fails with
+/// `400 Bad Request` if the key is invalid
+pub struct IdempotencyKey(pub Option<String>);
+
+/// Returns whether a key is made of visible ASCII characters, and isn't too
+/// long to store
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Idempotency-Key") {
+            Some(key) if is_valid_key(key) => {
+                Outcome::Success(IdempotencyKey(Some(key.to_string())))
+            }
+            Some(_) => Outcome::Error((Status::BadRequest, ())),
+            None => Outcome::Success(IdempotencyKey(None)),
+        }
+    }
+}
+
+/// Returns a hash of the request body, used to tell whether a key is being
+/// reused for a different request
+pub fn request_hash(body: &str) -> String {
+    hex::encode(Sha256::digest(body.as_bytes()))
+}
+
+/// What has already happened with a key
+pub enum Previous {
+    /// The key hasn't been used within the window
+    None,
+    /// The key was used for the same request, which got this response
+    Response(String),
+    /// The key was used for a different request
+    Mismatch,
+}
+
+/// Looks up what happened the last time the key was used. This needs to be
+/// called inside the transaction which makes the change, and it holds a lock
+/// on the key until the transaction ends so that retries which arrive at the
+/// same time wait for the first request to finish.
+pub async fn lookup(
+    conn: &mut Connection<Db>,
+    window: Duration,
+    user_id: &str,
+    key: &str,
+    request_hash: &str,
+) -> QueryResult<Previous> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>(format!("idempotency:{}:{}", user_id, key))
+        .execute(conn)
+        .await?;
+
+    // Keys are forgotten once the window has passed
+    let since = Utc::now().naive_utc() - window;
+    diesel::delete(schema::idempotency_keys::table)
+        .filter(schema::idempotency_keys::created_at.le(since))
+        .execute(conn)
+        .await?;
+
+    let previous: Option<(String, String)> = schema::idempotency_keys::table
+        .filter(schema::idempotency_keys::user_id.eq(user_id))
+        .filter(schema::idempotency_keys::key.eq(key))
+        .select((
+            schema::idempotency_keys::request_hash,
+            schema::idempotency_keys::response,
+        ))
+        .first(conn)
+        .await
+        .optional()?;
+
+    Ok(match previous {
+        Some((hash, response)) if hash == request_hash => Previous::Response(response),
+        Some(_) => Previous::Mismatch,
+        None => Previous::None,
+    })
+}
+
+/// Stores the response to a request, to be replayed for retries
+pub async fn store(
+    conn: &mut Connection<Db>,
+    user_id: &str,
+    key: &str,
+    request_hash: &str,
+    response: &str,
+) -> QueryResult<()> {
+    diesel::insert_into(schema::idempotency_keys::table)
+        .values((
+            schema::idempotency_keys::user_id.eq(user_id),
+            schema::idempotency_keys::key.eq(key),
+            schema::idempotency_keys::request_hash.eq(request_hash),
+            schema::idempotency_keys::response.eq(response),
+            schema::idempotency_keys::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! Handles authentication with usernames and passwords stored in the
+//! database, for deployments which don't have an OIDC server
+
+use std::io::{self, BufRead, Write};
+
+use anyhow::Context;
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+use figment::Figment;
+use rocket::fairing::AdHoc;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::response::status::NoContent;
+use rocket::response::Redirect;
+use rocket::serde::{json::Json, Deserialize};
+use rocket::tokio::task;
+use rocket::State;
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::{context, Template};
+use utoipa::ToSchema;
+
+use crate::admin::AdminRoute;
+use crate::api::API_LOCAL;
+use crate::auth::{safe_return_to, Admin, DEFAULT_RETURN_TO, USER_COOKIE};
+use crate::config::AppConfig;
+use crate::csrf::{self, CsrfForm};
+use crate::database::{Db, DisplayName, LocalUser, Session};
+use crate::error::AppError;
+use crate::ratelimit::{ApiCalls, Logins, RateLimit};
+use crate::utils::random_colour;
+
+/// Data which needs to be given when requesting "/login"
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct LoginData {
+    username: String,
+    password: String,
+}
+
+/// Hashes a password, this is slow on purpose so is run off the async
+/// threads
+async fn hash_password(password: String) -> anyhow::Result<String> {
+    Ok(task::spawn_blocking(move || password_auth::generate_hash(password)).await?)
+}
+
+/// Checks the password against the user's hash. If the user doesn't exist we
+/// still hash the password so it takes the same amount of time either way.
+async fn check_password(user: Option<LocalUser>, password: String) -> bool {
+    task::spawn_blocking(move || match user {
+        Some(user) => password_auth::verify_password(password, &user.password_hash).is_ok(),
+        None => {
+            password_auth::generate_hash(password);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Shows the username and password form
+#[get("/login?<return_to>", rank = 2)]
+fn login_page(return_to: Option<&str>) -> Template {
+    Template::render(
+        "login",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            homepage: return_to
+                .and_then(safe_return_to)
+                .unwrap_or_else(|| DEFAULT_RETURN_TO.to_string()),
+            name: "Sign in",
+        },
+    )
+}
+
+/// Logs the user in, starting a new session if the password is correct
+///
+/// This is only available when using local accounts.
+#[utoipa::path(
+    tag = "sessions",
+    request_body = LoginData,
+    responses(
+        (status = 204, description = "The user was logged in, and the session cookie set"),
+        (status = 401, description = "The username or password is incorrect", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+#[post("/login", data = "<info>")]
+async fn login(
+    _limit: RateLimit<Logins>,
+    config: &State<AppConfig>,
+    mut db: Connection<Db>,
+    jar: &CookieJar<'_>,
+    info: Json<LoginData>,
+) -> Result<NoContent, AppError> {
+    let LoginData { username, password } = info.into_inner();
+
+    let user = LocalUser::get(&mut db, &username).await;
+    if !check_password(user, password).await {
+        return Err(AppError::new(
+            Status::Unauthorized,
+            "Incorrect username or password",
+        ));
+    }
+
+    let _ = Session::delete_expired(
+        &mut db,
+        config.session_idle_timeout(),
+        config.session_absolute_timeout(),
+    )
+    .await;
+
+    let session = Session::new(username, None, None);
+    if let Err(e) = session.create(&mut db).await {
+        error!("Could not create session: {}", e);
+        return Err(AppError::internal("Could not start your session"));
+    }
+
+    // Local users are shown by their username, which is also their ID
+    if let Err(e) = DisplayName::set(&mut db, &session.user_id, &session.user_id).await {
+        error!("Could not store the display name: {}", e);
+    }
+
+    jar.add_private(Cookie::build((USER_COOKIE, session.id)).same_site(SameSite::Lax));
+    Ok(NoContent)
+}
+
+/// Lists the local users so administrators can manage them
+#[get("/users")]
+async fn users(admin: Admin, mut db: Connection<Db>) -> Template {
+    let users: Vec<_> = LocalUser::get_all(&mut db)
+        .await
+        .into_iter()
+        .map(|u| {
+            context! {
+                username: u.username,
+                created_at: u.created_at.format("%Y-%m-%d %H:%M").to_string(),
+            }
+        })
+        .collect();
+
+    Template::render(
+        "users",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            users: users,
+            csrf_token: csrf::token(&admin.0.session),
+            name: "Users",
+        },
+    )
+}
+
+/// Data which needs to be given when requesting "/users"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct SetPasswordData<'r> {
+    username: &'r str,
+    password: &'r str,
+}
+
+/// Allows an administrator to add a user or reset their password
+#[utoipa::path(
+    tag = "admin",
+    request_body(content = SetPasswordData, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the users page"),
+        (status = 400, description = "The username or password is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users", data = "<info>")]
+async fn set_password(
+    _limit: RateLimit<ApiCalls>,
+    admin: Admin,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    info: CsrfForm<SetPasswordData<'_>>,
+) -> Result<Redirect, AppError> {
+    if info.username.is_empty() || info.password.is_empty() {
+        return Err(AppError::new(
+            Status::BadRequest,
+            "A username and password are needed",
+        ));
+    }
+
+    let hash = hash_password(info.password.to_string())
+        .await
+        .map_err(|e| {
+            error!("Could not hash password: {}", e);
+            AppError::internal("Could not set the password")
+        })?;
+
+    LocalUser::set_password(&mut db, info.username, &hash)
+        .await
+        .map_err(|e| {
+            error!("Could not set password: {}", e);
+            AppError::internal("Could not set the password")
+        })?;
+
+    info!("{} set the password for {}", admin.0.id, info.username);
+    Ok(Redirect::to(uri!(admin_route.0.clone(), users)))
+}
+
+/// Data which needs to be given when requesting "/users/delete"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct DeleteUserData<'r> {
+    username: &'r str,
+}
+
+/// Allows an administrator to remove a user, also logging them out
+#[utoipa::path(
+    tag = "admin",
+    request_body(content = DeleteUserData, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the users page"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/users/delete", data = "<info>")]
+async fn delete_user(
+    _limit: RateLimit<ApiCalls>,
+    admin: Admin,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    info: CsrfForm<DeleteUserData<'_>>,
+) -> Result<Redirect, AppError> {
+    let res = match LocalUser::delete(&mut db, info.username).await {
+        Ok(_) => Session::delete_all(&mut db, info.username)
+            .await
+            .map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    res.map_err(|e| {
+        error!("Could not delete user: {}", e);
+        AppError::internal("Could not delete the user")
+    })?;
+
+    info!("{} deleted the user {}", admin.0.id, info.username);
+    Ok(Redirect::to(uri!(admin_route.0.clone(), users)))
+}
+
+/// Creates (or resets the password of) a user from the command line, reading
+/// the password from stdin. This allows the first administrator to be set up.
+pub async fn add_user_command(figment: &Figment, username: &str) -> anyhow::Result<()> {
+    let url: String = figment
+        .extract_inner("databases.diesel_postgres.url")
+        .context("Could not find the database URL")?;
+    let mut conn = AsyncPgConnection::establish(&url).await?;
+
+    print!("Password for {}: ", username);
+    io::stdout().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+
+    if password.is_empty() {
+        anyhow::bail!("The password cannot be empty");
+    }
+
+    let hash = hash_password(password).await?;
+    LocalUser::set_password(&mut conn, username, &hash).await?;
+
+    println!("Saved user {}", username);
+    Ok(())
+}
+
+/// Adds the endpoints for logging in with a username and password, and for
+/// administrators to manage the users
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Local Authentication Stage", |rocket| async {
+        let admin = AdminRoute::get(&rocket);
+
+        rocket
+            .mount("/", routes![login_page])
+            .mount(admin, routes![users])
+            .mount(API_LOCAL, routes![login, set_password, delete_user])
+    })
+}
@@ -4,29 +4,54 @@ extern crate rocket;
 use api::API_LOCAL;
 use diesel::prelude::*;
 use diesel::ExpressionMethods;
+use figment::Figment;
 use rocket::fairing::AdHoc;
 use rocket::fs::{relative, FileServer};
 use rocket::http::Status;
 use rocket::response::Redirect;
+use rocket::{Build, Rocket, State};
 use rocket_db_pools::diesel::prelude::RunQueryDsl;
 use rocket_db_pools::Connection;
-use rocket_dyn_templates::context;
 use rocket_dyn_templates::Template;
 
 mod admin;
 mod api;
 mod auth;
+mod cache;
+mod canonical;
 mod config;
+mod csrf;
 mod database;
+mod directory;
+mod error;
+mod hits;
+mod idempotency;
+mod local_auth;
+mod names;
+mod notify;
+mod openapi;
+mod quotas;
+mod ratelimit;
+mod reserved;
+mod rules;
 mod schema;
+mod suggestions;
+mod teams;
+mod titles;
 mod utils;
+mod webhooks;
 
 #[cfg(test)]
 mod tests;
 
+use crate::cache::RedirectCache;
 use crate::config::AppConfig;
 use crate::database::{Db, Result, Url};
-use crate::utils::random_colour;
+use crate::hits::HitCounter;
+use crate::ratelimit::{RateLimit, RateLimiter, Redirects};
+
+/// Where the files served at the root come from
+const STATIC_DIR: &str = relative!("static");
 
 #[get("/")]
 pub fn index() -> Redirect {
@@ -34,55 +59,85 @@ pub fn index() -> Redirect {
 }
 
 /// Handles any link that is not found elsewhere and looks it up in the
-/// database to redirect
+/// database to redirect. Links which don't exist are left to
+/// [`suggestions::not_found`].
 #[get("/<link>", rank = 100)]
-async fn redirect(mut db: Connection<Db>, link: &str) -> Result<Redirect, Status> {
-    let res: Result<Url, _> = schema::urls::table
-        .filter(schema::urls::name.eq(link))
-        .first(&mut db)
-        .await;
-
-    match res {
-        Ok(url) => Ok(Redirect::to(url.url)),
-        Err(_) => Err(Status::NotFound),
-    }
-}
+async fn redirect(
+    _limit: RateLimit<Redirects>,
+    config: &State<AppConfig>,
+    cache: &State<RedirectCache>,
+    hits: &State<HitCounter>,
+    mut db: Connection<Db>,
+    link: &str,
+) -> Result<Redirect, Status> {
+    let normalized = names::normalize(&config.names, link);
+    let target = match cache.get(&normalized) {
+        Some(target) => target,
+        None => {
+            let res: Result<Url, _> = schema::urls::table
+                .filter(schema::urls::normalized_name.eq(&normalized))
+                .first(&mut db)
+                .await;
 
-#[catch(500)]
-fn internal_error() -> Template {
-    Template::render(
-        "error/500",
-        context! {
-            colour: random_colour(),
-            name: "Oops",
-        },
-    )
-}
+            let target = match res {
+                Ok(url) => Some(url.url),
+                Err(diesel::result::Error::NotFound) => None,
+                Err(e) => {
+                    error!("Could not look up the link '{}': {}", link, e);
+                    return Err(Status::InternalServerError);
+                }
+            };
+            cache.insert(&normalized, target.clone());
+            target
+        }
+    };
 
-#[catch(404)]
-fn not_found() -> Template {
-    Template::render(
-        "error/404",
-        context! {
-            colour: random_colour(),
-            name: "Lost and Found",
-        },
-    )
+    let target = target.ok_or(Status::NotFound)?;
+    hits.record(&normalized);
+    Ok(Redirect::to(target))
 }
 
-/// Main function run by rocket to launch the application
-#[launch]
-fn rocket() -> _ {
-    let figment = config::get_figment();
-
+/// Builds the application from the configuration
+fn rocket(figment: Figment) -> Rocket<Build> {
     rocket::custom(figment)
         .attach(Template::fairing())
         .attach(AdHoc::config::<AppConfig>())
         .attach(admin::stage("/admin".to_string()))
         .attach(api::stage(API_LOCAL.to_string()))
         .attach(auth::stage())
+        .attach(teams::stage())
+        .attach(directory::stage())
+        .attach(webhooks::stage())
+        .attach(names::stage())
+        .attach(rules::stage())
+        .attach(canonical::stage())
+        .attach(titles::stage())
+        .attach(hits::stage())
         .attach(database::stage())
+        .attach(RateLimiter::in_memory())
+        .attach(cache::stage())
+        .attach(notify::stage())
+        .attach(openapi::stage())
         .mount("/", routes![index, redirect])
-        .mount("/", FileServer::from(relative!("static")))
-        .register("/", catchers![not_found, internal_error])
+        .mount("/", FileServer::from(STATIC_DIR))
+        .attach(reserved::stage(STATIC_DIR))
+        .register("/", catchers![error::catch_all, suggestions::not_found])
+}
+
+/// Launches the application, unless a command is given on the command line:
+///
+/// - `add-user <username>` creates a local user (or resets their password)
+#[rocket::main]
+async fn main() -> anyhow::Result<()> {
+    let figment = config::get_figment();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["add-user", username] => local_auth::add_user_command(&figment, username).await,
+        [] => {
+            rocket(figment).launch().await?;
+            Ok(())
+        }
+        _ => anyhow::bail!("Usage: link_shortener [add-user <username>]"),
+    }
 }
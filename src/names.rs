@@ -0,0 +1,160 @@
+//! Decides when two link names are the same link. Names are looked up by their
+//! normal form, which folds case and applies Unicode NFKC (depending on the
+//! configuration), and only one link can have each normal form. Names which
+//! could be mistaken for each other, like `paypal` and `pаypal` with a
+//! Cyrillic `а`, share a confusable skeleton from Unicode TR39, which is used
+//! to turn away look-alikes of existing names.
+
+use caseless::{default_case_fold_str, Caseless};
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use rocket_db_pools::diesel::result::{DatabaseErrorKind, Error};
+use rocket_db_pools::diesel::PgPool;
+use rocket_db_pools::Database;
+use unicode_normalization::UnicodeNormalization;
+use unicode_script::{Script, UnicodeScript};
+use unicode_security::MixedScript;
+
+use crate::config::{AppConfig, NameConfig};
+use crate::database::{Db, Url};
+
+/// Returns the form of a name which links are looked up by. With both
+/// settings on this is NFKC_Casefold, as in Unicode's compatibility caseless
+/// matching, so that e.g. `Straße` and `STRASSE` are the same name. Folding
+/// and normalising can each undo the other, which is why they're repeated.
+pub fn normalize(config: &NameConfig, name: &str) -> String {
+    match (config.case_insensitive, config.nfkc) {
+        (true, true) => name
+            .nfd()
+            .default_case_fold()
+            .nfkd()
+            .default_case_fold()
+            .nfkc()
+            .collect(),
+        (true, false) => default_case_fold_str(name),
+        (false, true) => name.nfkc().collect(),
+        (false, false) => name.to_string(),
+    }
+}
+
+/// Returns the confusable skeleton of a normalised name, names with the same
+/// skeleton look alike
+pub fn skeleton(normalized: &str) -> String {
+    unicode_security::skeleton(normalized).collect()
+}
+
+/// Returns whether a character is written in one of the allowed scripts. The
+/// ASCII digits and punctuation are shared by every script.
+fn script_allowed(scripts: &[String], c: char) -> bool {
+    if c.is_ascii() && c.script() == Script::Common {
+        return true;
+    }
+
+    scripts
+        .iter()
+        .any(|s| match s.eq_ignore_ascii_case("ascii") {
+            true => c.is_ascii(),
+            false => {
+                Script::from_full_name(s).is_some_and(|s| c.script_extension().contains_script(s))
+            }
+        })
+}
+
+/// Checks that a name can be used, returning why not otherwise. This only
+/// looks at the name itself, not the existing links it could be confused
+/// with.
+pub fn check(config: &NameConfig, name: &str) -> Result<(), String> {
+    let normalized = normalize(config, name);
+
+    // Normalising can turn allowed characters into ones which aren't, like
+    // '¼' into "1⁄4"
+    let valid = normalized
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err("Invalid characters in name!".to_string());
+    }
+
+    if !config.scripts.is_empty()
+        && !normalized
+            .chars()
+            .all(|c| script_allowed(&config.scripts, c))
+    {
+        return Err(format!(
+            "Names can only be written in {}",
+            config.scripts.join(", ")
+        ));
+    }
+
+    if config.reject_confusables && !normalized.as_str().is_single_script() {
+        return Err("Names can't mix scripts".to_string());
+    }
+
+    Ok(())
+}
+
+/// Brings the normal forms and skeletons of every link up to date with the
+/// configuration, which could have changed since they were stored. Links
+/// whose new normal form is already taken keep their old one, and are logged
+/// so they can be renamed.
+async fn update_all(pool: &PgPool, config: &NameConfig) -> anyhow::Result<()> {
+    let mut conn = pool.get().await?;
+
+    let mut updated = 0;
+    for (name, old_normalized, old_skeleton) in Url::get_all_names(&mut conn).await? {
+        let normalized = normalize(config, &name);
+        let skeleton = skeleton(&normalized);
+        if normalized == old_normalized && skeleton == old_skeleton {
+            continue;
+        }
+
+        match Url::set_normalized(&mut conn, &name, &normalized, &skeleton).await {
+            Ok(()) => updated += 1,
+            Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => warn!(
+                "The link '{}' is the same as another link once normalised, so it can't be \
+                 reached until one of them is renamed",
+                name
+            ),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if updated > 0 {
+        info!("Updated the normalised names of {} links", updated);
+    }
+    Ok(())
+}
+
+/// Checks the name settings, and updates the stored names to match them once
+/// the application has launched
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Link Names", |rocket| async {
+        if let Some(config) = rocket.state::<AppConfig>() {
+            for script in &config.names.scripts {
+                if !script.eq_ignore_ascii_case("ascii") && Script::from_full_name(script).is_none()
+                {
+                    warn!(
+                        "Unknown script '{}' in the allowed scripts for names",
+                        script
+                    );
+                }
+            }
+        }
+
+        rocket.attach(AdHoc::on_liftoff("Link Name Normalisation", |rocket| {
+            Box::pin(async move {
+                let (Some(db), Some(config)) = (Db::fetch(rocket), rocket.state::<AppConfig>())
+                else {
+                    return;
+                };
+
+                let (pool, config) = ((**db).clone(), config.names.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = update_all(&pool, &config).await {
+                        error!("Could not update the normalised link names: {}", e);
+                    }
+                });
+            })
+        }))
+    })
+}
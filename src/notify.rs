@@ -0,0 +1,184 @@
+//! Keeps each instance up to date with changes to links made by the others.
+//! A trigger on `urls` sends a Postgres `NOTIFY` whenever a link changes, and
+//! every instance `LISTEN`s for them to update its redirect cache and pass the
+//! change on to anything watching [`LinkEvents`].
+
+use std::future::poll_fn;
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket::tokio::{
+    self,
+    sync::{broadcast, mpsc},
+};
+use tokio_postgres::config::SslMode;
+use tokio_postgres::{AsyncMessage, Config, NoTls};
+use utoipa::ToSchema;
+
+use crate::cache::RedirectCache;
+use crate::config::{AppConfig, NameConfig};
+use crate::names;
+
+/// The channel the `urls` trigger notifies on
+const CHANNEL: &str = "link_changes";
+
+/// The shortest amount of time to wait before reconnecting after losing the
+/// connection, this is doubled after each consecutive failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The longest amount of time to wait between attempts to reconnect
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How many changes can be waiting for a slow subscriber before it misses
+/// some
+const EVENT_BUFFER: usize = 256;
+
+/// A change to a link, made by any instance
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct LinkChange {
+    /// Either "INSERT", "UPDATE" or "DELETE"
+    pub op: String,
+    pub name: String,
+}
+
+/// Broadcasts the changes to links as they arrive
+#[derive(Clone)]
+pub struct LinkEvents(broadcast::Sender<LinkChange>);
+
+impl LinkEvents {
+    pub fn subscribe(&self) -> broadcast::Receiver<LinkChange> {
+        self.0.subscribe()
+    }
+}
+
+/// Listens for changes until the connection is lost. This connects without
+/// TLS, the same as the database pool (diesel-async establishes its
+/// connections with `NoTls`), so it works wherever the pool does.
+async fn listen(
+    db_config: &Config,
+    cache: &RedirectCache,
+    events: &LinkEvents,
+    name_config: &NameConfig,
+) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = db_config.connect(NoTls).await?;
+
+    // The connection has to be polled for the client to make any progress,
+    // so it is driven from its own task which passes the messages back
+    let (sender, mut messages) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+    info!("Listening for link changes");
+
+    // Changes could have been missed while we weren't listening
+    cache.clear();
+
+    while let Some(message) = messages.recv().await {
+        match message? {
+            AsyncMessage::Notification(n) if n.channel() == CHANNEL => {
+                match json::from_str::<LinkChange>(n.payload()) {
+                    Ok(change) => {
+                        cache.invalidate(&names::normalize(name_config, &change.name));
+                        // It's fine if nobody is watching
+                        let _ = events.0.send(change);
+                    }
+                    Err(e) => warn!("Could not read link change '{}': {}", n.payload(), e),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps listening for changes, reconnecting with exponential backoff
+/// whenever the connection is lost
+async fn run(url: String, cache: RedirectCache, events: LinkEvents, name_config: NameConfig) {
+    let db_config: Config = match url.parse() {
+        Ok(db_config) => db_config,
+        Err(e) => {
+            error!(
+                "Could not read the database URL to listen for link changes: {}",
+                e
+            );
+            return;
+        }
+    };
+    if db_config.get_ssl_mode() == SslMode::Require {
+        error!(
+            "Can't listen for link changes, as the database URL requires TLS \
+             (sslmode=require) which isn't supported, the same as for the \
+             database pool"
+        );
+        return;
+    }
+
+    let mut retry_delay = MIN_RETRY_DELAY;
+
+    loop {
+        match listen(&db_config, &cache, &events, &name_config).await {
+            Ok(()) => {
+                warn!("Lost the connection for link changes, reconnecting");
+                retry_delay = MIN_RETRY_DELAY;
+            }
+            Err(e) => {
+                warn!(
+                    "Could not listen for link changes, retrying in {}s: {}",
+                    retry_delay.as_secs(),
+                    e
+                );
+            }
+        }
+
+        tokio::time::sleep(retry_delay).await;
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+/// Starts listening for changes once the application has launched
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Link Change Notifications", |rocket| async {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+
+        rocket.manage(LinkEvents(sender)).attach(AdHoc::on_liftoff(
+            "Link Change Listener",
+            |rocket| {
+                Box::pin(async move {
+                    let url: String = match rocket
+                        .figment()
+                        .extract_inner("databases.diesel_postgres.url")
+                    {
+                        Ok(url) => url,
+                        Err(e) => {
+                            error!("Could not find the database URL: {}", e);
+                            return;
+                        }
+                    };
+
+                    let (Some(cache), Some(events), Some(config)) = (
+                        rocket.state::<RedirectCache>(),
+                        rocket.state::<LinkEvents>(),
+                        rocket.state::<AppConfig>(),
+                    ) else {
+                        return;
+                    };
+
+                    tokio::spawn(run(
+                        url,
+                        cache.clone(),
+                        events.clone(),
+                        config.names.clone(),
+                    ));
+                })
+            },
+        ))
+    })
+}
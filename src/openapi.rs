@@ -0,0 +1,152 @@
+//! The OpenAPI description of the API, generated from the routes and the
+//! types they take and return. A copy is kept in `openapi.json` at the root of
+//! the repository, and the tests fail if it is out of date.
+
+use std::path::Path;
+
+use rocket::fairing::AdHoc;
+use rocket::http::ContentType;
+use rocket_dyn_templates::{context, Template};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::openapi::OpenApi as Spec;
+use utoipa::{Modify, OpenApi};
+
+use crate::api::{self, API_LOCAL};
+use crate::auth::USER_COOKIE;
+use crate::cache::CacheStats;
+use crate::csrf::NoFields;
+use crate::database::LinkOrder;
+use crate::error::{FieldError, Problem};
+use crate::notify::LinkChange;
+use crate::{local_auth, teams, webhooks, STATIC_DIR};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Link Shortener API",
+        description = "Creating, finding and managing short links",
+    ),
+    servers((url = "/api/v1")),
+    paths(
+        api::add,
+        api::delete,
+        api::check,
+        api::search,
+        api::link,
+        api::add_alias,
+        api::logout,
+        api::logout_all,
+        api::revoke_sessions,
+        api::cache_stats,
+        api::link_events,
+        local_auth::login,
+        local_auth::set_password,
+        local_auth::delete_user,
+        teams::create_team,
+        teams::delete_team,
+        teams::set_member,
+        teams::remove_member,
+        teams::add_prefix,
+        teams::remove_prefix,
+        webhooks::create_webhook,
+        webhooks::delete_webhook,
+    ),
+    components(schemas(
+        api::AddData,
+        api::AddPostResponse,
+        api::AliasData,
+        api::CheckResponse,
+        api::DeleteData,
+        api::LinkDetails,
+        api::LinkSummary,
+        api::RevokeData,
+        local_auth::DeleteUserData,
+        local_auth::LoginData,
+        local_auth::SetPasswordData,
+        teams::CreateTeamData,
+        teams::MemberData,
+        teams::PrefixData,
+        teams::RemoveMemberData,
+        teams::RuleData,
+        webhooks::WebhookData,
+        CacheStats,
+        FieldError,
+        LinkChange,
+        LinkOrder,
+        NoFields,
+        Problem,
+    )),
+    modifiers(&SessionCookie, &NoLicense),
+    tags(
+        (name = "links", description = "Creating and changing links"),
+        (name = "sessions", description = "Logging in and out"),
+        (name = "teams", description = "Sharing prefixes between groups of users"),
+        (name = "webhooks", description = "Telling other systems when links change"),
+        (name = "admin", description = "Only available to administrators"),
+    ),
+)]
+pub struct ApiDoc;
+
+/// Describes how requests are authenticated, which is with the session
+/// cookie set when logging in
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut Spec) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "session",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(USER_COOKIE))),
+            );
+        }
+    }
+}
+
+/// Leaves the license out, which would otherwise be taken from `Cargo.toml`
+/// and have an empty name as none is given there
+struct NoLicense;
+
+impl Modify for NoLicense {
+    fn modify(&self, openapi: &mut Spec) {
+        openapi.info.license = None;
+    }
+}
+
+/// Returns the description of the API as it is stored in `openapi.json`
+pub fn spec() -> String {
+    let mut spec = ApiDoc::openapi()
+        .to_pretty_json()
+        .expect("The OpenAPI description can be serialised");
+    spec.push('\n');
+    spec
+}
+
+/// The description of the API, for generating clients
+#[get("/openapi.json")]
+fn openapi_json() -> (ContentType, String) {
+    (ContentType::JSON, spec())
+}
+
+/// Browsable documentation for the API, using the copy of Swagger UI in
+/// `static/swagger-ui` if it has been added
+#[get("/docs")]
+fn docs() -> Template {
+    let swagger_ui = Path::new(STATIC_DIR)
+        .join("swagger-ui/swagger-ui-bundle.js")
+        .is_file();
+
+    Template::render(
+        "api_docs",
+        context! {
+            api: API_LOCAL,
+            name: "API Documentation",
+            swagger_ui: swagger_ui,
+        },
+    )
+}
+
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("API Documentation", |rocket| async {
+        rocket.mount(API_LOCAL, routes![openapi_json, docs])
+    })
+}
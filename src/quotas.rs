@@ -0,0 +1,214 @@
+//! Limits on how many links users can create, so a single user (or a script
+//! using their account) can't fill up the namespace
+
+use chrono::Utc;
+use diesel::sql_types::Text;
+use rocket::serde::Serialize;
+use rocket_db_pools::diesel::prelude::*;
+use rocket_db_pools::Connection;
+
+use crate::config::QuotaConfig;
+use crate::database::{Db, PrefixLink};
+use crate::rules::{self, RuleCache, RuleKind};
+use crate::schema;
+
+/// Reasons a link can't be created because of a quota
+pub enum QuotaError {
+    Exceeded(String),
+    Error(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for QuotaError {
+    fn from(value: diesel::result::Error) -> Self {
+        QuotaError::Error(value)
+    }
+}
+
+/// How many links match one of the user's prefix rules
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PrefixUsage {
+    pub prefix: String,
+    pub links: i64,
+}
+
+/// How many links a user has created, against the configured limits
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Usage {
+    pub links: i64,
+    pub max_links: Option<i64>,
+    pub recent: i64,
+    pub max_recent: Option<i64>,
+    pub window: String,
+    pub max_per_prefix: Option<i64>,
+    pub prefixes: Vec<PrefixUsage>,
+}
+
+/// Describes a number of seconds in the largest whole unit, leaving out the
+/// amount when it is one so it reads as "every day" or "the last 2 hours"
+pub fn describe_window(seconds: i64) -> String {
+    let (amount, unit) = match seconds {
+        s if s % (60 * 60 * 24) == 0 => (s / (60 * 60 * 24), "day"),
+        s if s % (60 * 60) == 0 => (s / (60 * 60), "hour"),
+        s if s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+
+    match amount {
+        1 => unit.to_string(),
+        n => format!("{} {}s", n, unit),
+    }
+}
+
+/// Returns how many links the user has created
+async fn count_links(conn: &mut Connection<Db>, user_id: &str) -> QueryResult<i64> {
+    schema::urls::table
+        .filter(schema::urls::user_id.eq(user_id))
+        .count()
+        .get_result(conn)
+        .await
+}
+
+/// Returns how many links the user has created within the time window
+async fn count_recent(
+    conn: &mut Connection<Db>,
+    quotas: &QuotaConfig,
+    user_id: &str,
+) -> QueryResult<i64> {
+    let since = Utc::now().naive_utc() - quotas.window();
+
+    schema::urls::table
+        .filter(schema::urls::user_id.eq(user_id))
+        .filter(schema::urls::created_at.gt(since))
+        .count()
+        .get_result(conn)
+        .await
+}
+
+/// Returns how many links, by anyone, match a prefix rule. Rules which allow
+/// any name aren't really prefixes so aren't counted. Names are matched with
+/// the rule's own compiled pattern, after the database has narrowed them down
+/// to the ones starting with the rule's literal text.
+async fn count_matching(
+    conn: &mut Connection<Db>,
+    patterns: &RuleCache,
+    rule: &PrefixLink,
+) -> QueryResult<Option<i64>> {
+    let Some(kind) = RuleKind::parse(&rule.kind) else {
+        return Ok(None);
+    };
+    if kind == RuleKind::Prefix && rule.prefix.is_empty() {
+        return Ok(None);
+    }
+    let Ok(pattern) = patterns.get(kind, &rule.prefix) else {
+        return Ok(None);
+    };
+
+    let start = rules::literal_prefix(kind, &rule.prefix);
+    let names: Vec<String> = schema::urls::table
+        .filter(schema::urls::name.like(format!("{}%", escape_like(start))))
+        .select(schema::urls::name)
+        .get_results(conn)
+        .await?;
+
+    Ok(Some(
+        names.iter().filter(|name| pattern.is_match(name)).count() as i64,
+    ))
+}
+
+/// Escapes the characters which have a special meaning in a `LIKE` pattern
+fn escape_like(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Checks that creating another link wouldn't take the user over any of the
+/// quotas. This needs to be called inside the transaction which creates the
+/// link, and it holds a lock for the user until the transaction ends so that
+/// requests made at the same time can't both get through.
+pub async fn enforce(
+    conn: &mut Connection<Db>,
+    patterns: &RuleCache,
+    quotas: &QuotaConfig,
+    user_id: &str,
+    rule: Option<&PrefixLink>,
+) -> Result<(), QuotaError> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>(user_id)
+        .execute(conn)
+        .await?;
+
+    if let Some(max) = quotas.max_links {
+        if count_links(conn, user_id).await? >= max {
+            return Err(QuotaError::Exceeded(format!(
+                "You have reached your limit of {} links",
+                max
+            )));
+        }
+    }
+
+    if let Some(max) = quotas.max_links_per_window {
+        if count_recent(conn, quotas, user_id).await? >= max {
+            return Err(QuotaError::Exceeded(format!(
+                "You can only create {} links every {}",
+                max,
+                describe_window(quotas.window)
+            )));
+        }
+    }
+
+    if let (Some(max), Some(rule)) = (quotas.max_links_per_prefix, rule) {
+        if count_matching(conn, patterns, rule)
+            .await?
+            .is_some_and(|n| n >= max)
+        {
+            return Err(QuotaError::Exceeded(format!(
+                "The {} '{}' already has the maximum of {} links",
+                rule.kind, rule.prefix, max
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the user's current usage, along with the usage of each prefix rule
+/// they can create links with
+pub async fn usage(
+    conn: &mut Connection<Db>,
+    patterns: &RuleCache,
+    quotas: &QuotaConfig,
+    user_id: &str,
+    rules: &[PrefixLink],
+) -> Usage {
+    let mut prefixes = Vec::new();
+    if quotas.max_links_per_prefix.is_some() {
+        for rule in rules.iter().filter(|r| !r.deny) {
+            if let Ok(Some(links)) = count_matching(conn, patterns, rule).await {
+                prefixes.push(PrefixUsage {
+                    prefix: rule.prefix.clone(),
+                    links,
+                });
+            }
+        }
+    }
+
+    Usage {
+        links: count_links(conn, user_id).await.unwrap_or_default(),
+        max_links: quotas.max_links,
+        recent: count_recent(conn, quotas, user_id)
+            .await
+            .unwrap_or_default(),
+        max_recent: quotas.max_links_per_window,
+        window: describe_window(quotas.window),
+        max_per_prefix: quotas.max_links_per_prefix,
+        prefixes,
+    }
+}
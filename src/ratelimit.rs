@@ -0,0 +1,278 @@
+//! Rate limiting for the redirects and the API. Each group of routes has its
+//! own token buckets, keyed by the client's IP address, or by the user for
+//! API requests from someone who is logged in. Requests which are over the
+//! limit get a `429` with a `Retry-After` header.
+//!
+//! The buckets are kept in a [`RateLimitStore`], which is in memory by
+//! default, but can be swapped for one shared between instances by attaching
+//! the [`RateLimiter`] fairing with a different store.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Build, Response, Rocket};
+
+use crate::auth::User;
+use crate::config::{AppConfig, BucketConfig};
+
+/// How many buckets the in-memory store holds before it clears out the ones
+/// which have refilled
+const MEMORY_STORE_PRUNE_SIZE: usize = 10_000;
+
+/// Somewhere to keep the token buckets
+#[rocket::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket with the given key, or returns how long
+    /// it will be until one is available if the bucket is empty
+    async fn take(&self, key: &str, limit: &BucketConfig) -> Result<(), Duration>;
+}
+
+/// A token bucket, which starts full and refills at a steady rate
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub fn new(limit: &BucketConfig, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Adds the tokens which have been earned since the bucket was last used
+    fn refill(&mut self, limit: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let rate = limit.per_minute as f64 / 60.0;
+
+        self.tokens = (self.tokens + elapsed * rate).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Returns whether the bucket has refilled completely, in which case it
+    /// doesn't need to be stored
+    pub fn is_full(&mut self, limit: &BucketConfig, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= limit.burst as f64
+    }
+
+    /// Takes a token, or returns how long until the next one is available
+    pub fn take(&mut self, limit: &BucketConfig, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if limit.per_minute == 0 {
+            return Err(Duration::from_secs(60));
+        }
+        let rate = limit.per_minute as f64 / 60.0;
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+    }
+}
+
+/// Keeps the buckets in this instance's memory
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, (Bucket, BucketConfig)>>,
+}
+
+#[rocket::async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, key: &str, limit: &BucketConfig) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MEMORY_STORE_PRUNE_SIZE {
+            buckets.retain(|_, (bucket, limit)| !bucket.is_full(limit, now));
+        }
+
+        let (bucket, _) = buckets
+            .entry(key.to_string())
+            .or_insert_with(|| (Bucket::new(limit, now), *limit));
+        bucket.take(limit, now)
+    }
+}
+
+/// Works out the address of the client, following `X-Forwarded-For` back
+/// through any trusted proxies
+pub fn client_ip(
+    remote: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let remote = remote?;
+    if !trusted(&remote) {
+        return Some(remote);
+    }
+
+    // Each proxy appends the address it received the request from, so the
+    // client is the last one which wasn't added by a proxy we trust
+    let mut client = remote;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) if trusted(&ip) => client = ip,
+            Ok(ip) => return Some(ip),
+            Err(_) => break,
+        }
+    }
+
+    Some(client)
+}
+
+/// The state used by the [`RateLimit`] guard
+struct RateLimits {
+    store: Arc<dyn RateLimitStore>,
+    trusted_proxies: Vec<IpNet>,
+    groups: HashMap<String, BucketConfig>,
+}
+
+/// How long the client has to wait, when a request has been limited
+struct RetryAfter(Option<Duration>);
+
+/// A group of routes which share a rate limit
+pub trait RouteGroup: Send + Sync + 'static {
+    /// The name of the group in the configuration
+    const NAME: &'static str;
+    /// Whether logged in users get their own bucket, rather than sharing one
+    /// with everyone else at their address
+    const BY_USER: bool;
+}
+
+/// Following links
+pub struct Redirects;
+
+impl RouteGroup for Redirects {
+    const NAME: &'static str = "redirect";
+    const BY_USER: bool = false;
+}
+
+/// Calls to the API, other than logging in
+pub struct ApiCalls;
+
+impl RouteGroup for ApiCalls {
+    const NAME: &'static str = "api";
+    const BY_USER: bool = true;
+}
+
+/// Attempts to log in with a password
+pub struct Logins;
+
+impl RouteGroup for Logins {
+    const NAME: &'static str = "login";
+    const BY_USER: bool = false;
+}
+
+/// Request guard which takes a token from the group's bucket, failing with
+/// `429 Too Many Requests` when it is empty
+pub struct RateLimit<G: RouteGroup>(PhantomData<G>);
+
+#[rocket::async_trait]
+impl<'r, G: RouteGroup> FromRequest<'r> for RateLimit<G> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(limits) = request.rocket().state::<RateLimits>() else {
+            return Outcome::Success(RateLimit(PhantomData));
+        };
+        let Some(limit) = limits.groups.get(G::NAME) else {
+            return Outcome::Success(RateLimit(PhantomData));
+        };
+
+        let user = match G::BY_USER {
+            true => request.guard::<User>().await.succeeded(),
+            false => None,
+        };
+        let key = match user {
+            Some(user) => format!("{}:user:{}", G::NAME, user.id),
+            None => {
+                let ip = client_ip(
+                    request.remote().map(|r| r.ip()),
+                    request.headers().get_one("X-Forwarded-For"),
+                    &limits.trusted_proxies,
+                );
+                match ip {
+                    Some(ip) => format!("{}:ip:{}", G::NAME, ip),
+                    None => format!("{}:ip:unknown", G::NAME),
+                }
+            }
+        };
+
+        match limits.store.take(&key, limit).await {
+            Ok(()) => Outcome::Success(RateLimit(PhantomData)),
+            Err(retry_after) => {
+                info!("Rate limited {}", key);
+                request.local_cache(|| RetryAfter(Some(retry_after)));
+                Outcome::Error((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+/// Sets up the [`RateLimit`] guards with somewhere to store the buckets, and
+/// tells limited clients when they can try again
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// Uses the given store, for example to share limits between instances
+    pub fn new(store: impl RateLimitStore + 'static) -> Self {
+        RateLimiter {
+            store: Arc::new(store),
+        }
+    }
+
+    /// Keeps the buckets in memory, so each instance has its own limits
+    pub fn in_memory() -> Self {
+        RateLimiter::new(MemoryStore::default())
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limiter",
+            kind: Kind::Ignite | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config: AppConfig = match rocket.figment().extract() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Could not read the rate limits: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(RateLimits {
+            store: self.store.clone(),
+            trusted_proxies: config.rate_limits.trusted_proxies,
+            groups: config.rate_limits.groups,
+        }))
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let RetryAfter(Some(retry_after)) = request.local_cache(|| RetryAfter(None)) {
+            // Round up so clients don't come back a moment too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.set_header(Header::new("Retry-After", seconds.to_string()));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! The names which can't be used for links because something else is served
+//! there. These are worked out from the routes which are mounted and the files
+//! in `static/` when the application starts, along with any extra names in
+//! the configuration.
+
+use std::collections::HashSet;
+use std::fs;
+
+use rocket::fairing::AdHoc;
+use rocket_db_pools::diesel::PgPool;
+use rocket_db_pools::Database;
+
+use crate::config::{AppConfig, NameConfig};
+use crate::database::{Db, Url};
+use crate::names;
+
+/// The normalised names which are reserved
+pub struct ReservedNames(HashSet<String>);
+
+impl ReservedNames {
+    /// Reserves the first segment of each path, and every other name given
+    pub fn new<'a>(
+        config: &NameConfig,
+        paths: impl IntoIterator<Item = &'a str>,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let reserved = paths
+            .into_iter()
+            .filter_map(first_segment)
+            .chain(names)
+            .map(|name| names::normalize(config, name))
+            .collect();
+
+        ReservedNames(reserved)
+    }
+
+    /// Returns whether a normalised name is reserved
+    pub fn contains(&self, normalized: &str) -> bool {
+        self.0.contains(normalized)
+    }
+}
+
+/// Returns the first segment of a route's path, unless it is dynamic (in
+/// which case the route doesn't take any names away from links)
+fn first_segment(path: &str) -> Option<&str> {
+    let segment = path.trim_start_matches('/').split('/').next()?;
+    match segment.is_empty() || segment.starts_with('<') {
+        true => None,
+        false => Some(segment),
+    }
+}
+
+/// Returns the names of the files and directories at the top of `dir`
+fn static_names(dir: &str) -> Vec<String> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect(),
+        Err(e) => {
+            warn!("Could not list the static files in '{}': {}", dir, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Logs the existing links which have a reserved name, as they can't be
+/// reached
+async fn check_links(pool: &PgPool, reserved: &ReservedNames) -> anyhow::Result<()> {
+    let mut conn = pool.get().await?;
+
+    for (name, normalized, _) in Url::get_all_names(&mut conn).await? {
+        if reserved.contains(&normalized) {
+            warn!(
+                "The link '{}' has a reserved name, so it can't be reached until it is renamed",
+                name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Works out the reserved names from what is being served from `static_dir`,
+/// and the routes which are mounted by the other stages
+pub fn stage(static_dir: &'static str) -> AdHoc {
+    AdHoc::on_ignite("Reserved Names", move |rocket| async move {
+        // Stages can attach other stages while igniting, which mount routes
+        // after this one has run. They have all been attached by now though,
+        // so a stage attached here runs after them.
+        rocket.attach(AdHoc::on_ignite(
+            "Reserved Names From Routes",
+            move |rocket| async move {
+                let config: AppConfig = rocket
+                    .figment()
+                    .extract()
+                    .expect("Could not find App Config");
+
+                let static_names = static_names(static_dir);
+                let paths: Vec<String> =
+                    rocket.routes().map(|r| r.uri.path().to_string()).collect();
+                let reserved = ReservedNames::new(
+                    &config.names,
+                    paths.iter().map(String::as_str),
+                    static_names
+                        .iter()
+                        .chain(&config.reserved_names)
+                        .map(String::as_str),
+                );
+
+                rocket
+                    .manage(reserved)
+                    .attach(AdHoc::on_liftoff("Reserved Name Check", |rocket| {
+                        Box::pin(async move {
+                            let (Some(db), Some(reserved)) =
+                                (Db::fetch(rocket), rocket.state::<ReservedNames>())
+                            else {
+                                return;
+                            };
+
+                            if let Err(e) = check_links(db, reserved).await {
+                                error!("Could not check links for reserved names: {}", e);
+                            }
+                        })
+                    }))
+            },
+        ))
+    })
+}
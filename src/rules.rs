@@ -0,0 +1,227 @@
+//! Decides which link names a user is allowed to create from the prefix rules
+//! they have been given. Rules are either plain prefixes, glob patterns (such
+//! as `team-*-docs`) or regular expressions, and can deny names as well as
+//! allow them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use regex::{Regex, RegexBuilder};
+use rocket::fairing::AdHoc;
+
+use crate::database::PrefixLink;
+
+/// The largest a compiled regular expression rule is allowed to be, so a
+/// badly written rule can't use up all the memory
+const REGEX_SIZE_LIMIT: usize = 1 << 16;
+/// How many compiled patterns are kept before the cache is emptied, which only
+/// happens if many rules have been changed since the server started
+const MAX_CACHED_PATTERNS: usize = 10_000;
+
+/// How the pattern of a rule is matched against a link name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RuleKind {
+    /// The name has to start with the pattern, an empty prefix matches
+    /// everything
+    Prefix,
+    /// `*` matches any number of characters and `?` matches exactly one
+    Glob,
+    /// A regular expression which has to match the whole name
+    Regex,
+}
+
+impl RuleKind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "prefix" => Some(RuleKind::Prefix),
+            "glob" => Some(RuleKind::Glob),
+            "regex" => Some(RuleKind::Regex),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleKind::Prefix => "prefix",
+            RuleKind::Glob => "glob",
+            RuleKind::Regex => "regex",
+        }
+    }
+}
+
+/// The outcome of checking a name against a user's rules, along with the rule
+/// which decided it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allowed(String),
+    Denied(String),
+    TooLong(String, usize),
+    NoMatch,
+}
+
+impl Decision {
+    /// Explains the decision in a way which can be shown to the user
+    pub fn reason(&self) -> String {
+        match self {
+            Decision::Allowed(rule) => format!("Allowed by {}", rule),
+            Decision::Denied(rule) => format!("Blocked by {}", rule),
+            Decision::TooLong(rule, max) => {
+                format!("Names from {} can be at most {} characters", rule, max)
+            }
+            Decision::NoMatch => "None of your prefixes allow this name".to_string(),
+        }
+    }
+}
+
+/// Turns a glob pattern into an equivalent regular expression
+fn glob_to_regex(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len() + 8);
+    for c in pattern.chars() {
+        match c {
+            '*' => out.push_str(".*"),
+            '?' => out.push('.'),
+            c => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    out
+}
+
+/// Returns text which every name matching the rule starts with, so names can
+/// be narrowed down before matching them. This is empty for regular
+/// expressions, which aren't picked apart.
+pub fn literal_prefix(kind: RuleKind, pattern: &str) -> &str {
+    match kind {
+        RuleKind::Prefix => pattern,
+        RuleKind::Glob => pattern
+            .find(['*', '?'])
+            .map_or(pattern, |end| &pattern[..end]),
+        RuleKind::Regex => "",
+    }
+}
+
+/// Compiles a rule's pattern into a regular expression which has to match the
+/// whole name
+pub fn compile(kind: RuleKind, pattern: &str) -> Result<Regex, regex::Error> {
+    let inner = match kind {
+        RuleKind::Prefix => format!("{}.*", regex::escape(pattern)),
+        RuleKind::Glob => glob_to_regex(pattern),
+        RuleKind::Regex => pattern.to_string(),
+    };
+
+    RegexBuilder::new(&format!("^(?:{})$", inner))
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// The compiled patterns of the rules which have been used, so that each one
+/// is only compiled once rather than on every check
+#[derive(Default)]
+pub struct RuleCache(RwLock<HashMap<(RuleKind, String), Arc<Regex>>>);
+
+impl RuleCache {
+    /// Returns the compiled pattern for a rule, compiling it if it hasn't
+    /// been used before
+    pub fn get(&self, kind: RuleKind, pattern: &str) -> Result<Arc<Regex>, regex::Error> {
+        let key = (kind, pattern.to_string());
+        if let Some(re) = self.0.read().unwrap().get(&key) {
+            return Ok(re.clone());
+        }
+
+        let re = Arc::new(compile(kind, pattern)?);
+        let mut patterns = self.0.write().unwrap();
+        if patterns.len() >= MAX_CACHED_PATTERNS {
+            patterns.clear();
+        }
+        patterns.insert(key, re.clone());
+        Ok(re)
+    }
+}
+
+/// Checks that a rule can be stored, returning a message for the user if not.
+/// The pattern is compiled into the cache, ready for the rule being used.
+pub fn validate(
+    cache: &RuleCache,
+    kind: &str,
+    pattern: &str,
+    max_length: Option<i32>,
+) -> Result<RuleKind, String> {
+    let kind = RuleKind::parse(kind).ok_or_else(|| format!("Unknown rule kind '{}'", kind))?;
+
+    if max_length.is_some_and(|m| m < 1) {
+        return Err("The maximum length has to be at least 1".to_string());
+    }
+
+    cache
+        .get(kind, pattern)
+        .map(|_| kind)
+        .map_err(|e| format!("Invalid pattern: {}", e))
+}
+
+/// Describes a rule for use in a [`Decision`]
+pub fn describe(rule: &PrefixLink) -> String {
+    format!(
+        "{}{} '{}'",
+        if rule.deny { "deny " } else { "" },
+        rule.kind,
+        rule.prefix
+    )
+}
+
+/// Returns whether a rule's pattern matches the name, rules which can't be
+/// understood never match
+fn matches(cache: &RuleCache, rule: &PrefixLink, name: &str) -> bool {
+    let Some(kind) = RuleKind::parse(&rule.kind) else {
+        warn!("Unknown kind '{}' for rule '{}'", rule.kind, rule.prefix);
+        return false;
+    };
+
+    match cache.get(kind, &rule.prefix) {
+        Ok(re) => re.is_match(name),
+        Err(e) => {
+            warn!("Could not compile rule '{}': {}", rule.prefix, e);
+            false
+        }
+    }
+}
+
+/// Checks a name against a set of rules. Deny rules always win over allow
+/// rules, and an allow rule with a maximum length only allows names which
+/// fit within it.
+pub fn check(cache: &RuleCache, rules: &[PrefixLink], name: &str) -> Decision {
+    match allowed_by(cache, rules, name) {
+        Ok(rule) => Decision::Allowed(describe(rule)),
+        Err(decision) => decision,
+    }
+}
+
+/// Like [`check`], but returns the rule which allowed the name
+pub fn allowed_by<'a>(
+    cache: &RuleCache,
+    rules: &'a [PrefixLink],
+    name: &str,
+) -> Result<&'a PrefixLink, Decision> {
+    let matching: Vec<&PrefixLink> = rules.iter().filter(|r| matches(cache, r, name)).collect();
+
+    if let Some(rule) = matching.iter().find(|r| r.deny) {
+        return Err(Decision::Denied(describe(rule)));
+    }
+
+    let length = name.chars().count();
+    let fits = |r: &&&PrefixLink| r.max_length.is_none_or(|m| length <= m as usize);
+
+    if let Some(rule) = matching.iter().find(fits) {
+        return Ok(rule);
+    }
+
+    Err(match matching.first() {
+        Some(rule) => Decision::TooLong(describe(rule), rule.max_length.unwrap_or(0) as usize),
+        None => Decision::NoMatch,
+    })
+}
+
+/// Adds the cache of compiled rule patterns
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Rule Patterns", |rocket| async {
+        rocket.manage(RuleCache::default())
+    })
+}
@@ -1,9 +1,95 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    display_names (user_id) {
+        user_id -> Varchar,
+        display_name -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    idempotency_keys (user_id, key) {
+        user_id -> Varchar,
+        key -> Varchar,
+        request_hash -> Varchar,
+        response -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    link_hits (name) {
+        name -> Varchar,
+        hits -> Int8,
+        last_hit_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    local_users (username) {
+        username -> Varchar,
+        password_hash -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    logout_tokens (issuer, jti) {
+        issuer -> Varchar,
+        jti -> Varchar,
+        expires_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     prefixes (user_id, prefix) {
         user_id -> Varchar,
         prefix -> Text,
+        kind -> Varchar,
+        deny -> Bool,
+        max_length -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        created_at -> Timestamp,
+        last_seen -> Timestamp,
+        refresh_token -> Nullable<Text>,
+        expires_at -> Nullable<Timestamp>,
+        id_token -> Nullable<Text>,
+        sid -> Nullable<Varchar>,
+        provider -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    team_members (team_id, user_id) {
+        team_id -> Int4,
+        user_id -> Varchar,
+        is_admin -> Bool,
+        synced -> Bool,
+    }
+}
+
+diesel::table! {
+    team_prefixes (team_id, prefix) {
+        team_id -> Int4,
+        prefix -> Text,
+        kind -> Varchar,
+        deny -> Bool,
+        max_length -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    teams (id) {
+        id -> Int4,
+        name -> Varchar,
+        oidc_group -> Nullable<Varchar>,
     }
 }
 
@@ -11,10 +97,66 @@ diesel::table! {
     urls (name) {
         name -> Varchar,
         url -> Text,
+        user_id -> Nullable<Varchar>,
+        team_id -> Nullable<Int4>,
+        created_at -> Timestamp,
+        normalized_name -> Varchar,
+        skeleton -> Varchar,
+        canonical_url -> Text,
+        alias_of -> Nullable<Varchar>,
+        title -> Nullable<Varchar>,
+        description -> Nullable<Text>,
+        tags -> Array<Text>,
+        listed -> Bool,
     }
 }
 
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int8,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Text,
+        created_at -> Timestamp,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+        failed -> Bool,
+        last_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        user_id -> Varchar,
+        url -> Text,
+        secret -> Varchar,
+        events -> Array<Text>,
+        all_links -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(link_hits -> urls (name));
+diesel::joinable!(team_members -> teams (team_id));
+diesel::joinable!(team_prefixes -> teams (team_id));
+diesel::joinable!(urls -> teams (team_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
+    display_names,
+    idempotency_keys,
+    link_hits,
+    local_users,
+    logout_tokens,
     prefixes,
+    sessions,
+    team_members,
+    team_prefixes,
+    teams,
     urls,
+    webhook_deliveries,
+    webhooks,
 );
@@ -0,0 +1,78 @@
+//! Helps people who mistype a link, by suggesting the links with the closest
+//! names on the page saying it doesn't exist. Names are compared with
+//! trigrams, and only listed links are suggested, to logged in users unless
+//! `suggestions` is turned off. Logged in users who could create a link with
+//! the name are offered to.
+
+use rocket::http::Status;
+use rocket::request::{Outcome, Request};
+use rocket::serde::json::json;
+use rocket_db_pools::Connection;
+
+use crate::admin::{self, AdminRoute};
+use crate::api;
+use crate::auth::User;
+use crate::config::AppConfig;
+use crate::database::{Db, Url};
+use crate::error::{prefers_json, AppError};
+use crate::names;
+use crate::reserved::ReservedNames;
+use crate::rules::RuleCache;
+
+/// The most names which are suggested
+const MAX_SUGGESTIONS: i64 = 5;
+
+/// Returns the name of the link which was asked for, if the path could be one
+fn requested_name(request: &Request<'_>) -> Option<String> {
+    let mut segments = request.uri().path().segments();
+    let name = segments.next().filter(|s| !s.is_empty())?;
+    match segments.next() {
+        Some(_) => None,
+        None => Some(name.to_string()),
+    }
+}
+
+/// Shows the page for links which don't exist, with the names of links which
+/// were perhaps meant instead
+#[catch(404)]
+pub async fn not_found(request: &Request<'_>) -> AppError {
+    let name = match requested_name(request) {
+        Some(name) if !prefers_json(request) => name,
+        _ => return AppError::from(Status::NotFound),
+    };
+    let error = AppError::new(Status::NotFound, "There is no link with this name");
+
+    // Suggestions could give away the names of links, so only logged in users
+    // are shown them, as they can search the links anyway
+    let Outcome::Success(user) = request.guard::<User>().await else {
+        return error;
+    };
+
+    let rocket = request.rocket();
+    let (Outcome::Success(mut db), Some(config), Some(reserved), Some(patterns), Some(admin)) = (
+        request.guard::<Connection<Db>>().await,
+        rocket.state::<AppConfig>(),
+        rocket.state::<ReservedNames>(),
+        rocket.state::<RuleCache>(),
+        rocket.state::<AdminRoute>(),
+    ) else {
+        return error;
+    };
+
+    let suggestions = match config.suggestions {
+        true => {
+            let normalized = names::normalize(&config.names, &name);
+            Url::similar(&mut db, &normalized, MAX_SUGGESTIONS).await
+        }
+        false => Vec::new(),
+    };
+    let create_url = api::can_create(&mut db, config, reserved, patterns, &user, &name)
+        .await
+        .then(|| uri!(admin.0.clone(), admin::index(Some(&name))).to_string());
+
+    error.with_page_data(json!({
+        "name": name,
+        "suggestions": suggestions,
+        "create_url": create_url,
+    }))
+}
@@ -0,0 +1,374 @@
+//! Handles teams, which let groups of users share prefixes and links. Teams
+//! are created by administrators, and their members are managed by the team's
+//! own admins or synced from OIDC groups.
+
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::State;
+use rocket_db_pools::Connection;
+use rocket_dyn_templates::{context, Template};
+use utoipa::ToSchema;
+
+use crate::admin::AdminRoute;
+use crate::api::API_LOCAL;
+use crate::auth::{Admin, User};
+use crate::config::AppConfig;
+use crate::csrf::{self, CsrfForm, NoFields};
+use crate::database::{Db, Team, TeamMember};
+use crate::error::AppError;
+use crate::ratelimit::{ApiCalls, RateLimit};
+use crate::rules::{self, RuleCache};
+use crate::utils::random_colour;
+
+/// Returns whether the user can manage the team's members, which they can if
+/// they are an administrator or one of the team's admins
+async fn can_manage(
+    conn: &mut Connection<Db>,
+    config: &AppConfig,
+    user: &User,
+    team_id: i32,
+) -> bool {
+    if config.is_admin(&user.id) {
+        return true;
+    }
+
+    TeamMember::get(conn, team_id, &user.id)
+        .await
+        .is_some_and(|m| m.is_admin)
+}
+
+/// Turns a database error into one which can be returned to the user
+fn db_error(e: diesel::result::Error) -> AppError {
+    error!("Could not update team: {}", e);
+    AppError::internal("Could not update the team")
+}
+
+/// Lists the teams the user is in, or every team for administrators
+#[get("/teams")]
+async fn teams(config: &State<AppConfig>, user: User, mut db: Connection<Db>) -> Template {
+    let is_admin = config.is_admin(&user.id);
+    let teams = if is_admin {
+        Team::get_all(&mut db).await
+    } else {
+        Team::get_for_user(&mut db, &user.id).await
+    };
+
+    Template::render(
+        "teams",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            teams: teams,
+            is_admin: is_admin,
+            csrf_token: csrf::token(&user.session),
+            name: "Teams",
+        },
+    )
+}
+
+/// Shows a team's members and prefixes, along with the forms to change them
+/// if the user is allowed to
+#[get("/teams/<id>")]
+async fn team(
+    config: &State<AppConfig>,
+    user: User,
+    mut db: Connection<Db>,
+    id: i32,
+) -> Result<Template, AppError> {
+    let not_found = || AppError::new(Status::NotFound, "This team does not exist");
+    let team = Team::get(&mut db, id).await.ok_or_else(not_found)?;
+
+    let is_admin = config.is_admin(&user.id);
+    let member = TeamMember::get(&mut db, id, &user.id).await;
+    if !is_admin && member.is_none() {
+        return Err(not_found());
+    }
+
+    Ok(Template::render(
+        "team",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            members: TeamMember::get_all(&mut db, id).await,
+            prefixes: Team::prefixes(&mut db, id).await,
+            can_manage: is_admin || member.is_some_and(|m| m.is_admin),
+            is_admin: is_admin,
+            csrf_token: csrf::token(&user.session),
+            name: team.name.clone(),
+            team: team,
+        },
+    ))
+}
+
+/// Data which needs to be given when requesting "/teams"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct CreateTeamData<'r> {
+    name: &'r str,
+    oidc_group: Option<&'r str>,
+}
+
+/// Allows an administrator to create a new team
+#[utoipa::path(
+    tag = "teams",
+    request_body(content = CreateTeamData, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the new team's page"),
+        (status = 400, description = "The team has no name", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/teams", data = "<info>")]
+async fn create_team(
+    _limit: RateLimit<ApiCalls>,
+    admin: Admin,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    info: CsrfForm<CreateTeamData<'_>>,
+) -> Result<Redirect, AppError> {
+    if info.name.is_empty() {
+        return Err(AppError::new(Status::BadRequest, "The team needs a name"));
+    }
+
+    let oidc_group = info.oidc_group.filter(|g| !g.is_empty());
+    let id = Team::create(&mut db, info.name, oidc_group)
+        .await
+        .map_err(db_error)?;
+
+    info!("{} created the team {}", admin.0.id, info.name);
+    Ok(Redirect::to(uri!(admin_route.0.clone(), team(id))))
+}
+
+/// Allows an administrator to remove a team
+#[utoipa::path(
+    tag = "teams",
+    request_body(content = NoFields, content_type = "application/x-www-form-urlencoded"),
+    params(("id", description = "The ID of the team")),
+    responses(
+        (status = 303, description = "Redirects to the teams page"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/teams/<id>/delete", data = "<_csrf>")]
+async fn delete_team(
+    _limit: RateLimit<ApiCalls>,
+    admin: Admin,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    id: i32,
+    _csrf: CsrfForm<NoFields>,
+) -> Result<Redirect, AppError> {
+    Team::delete(&mut db, id).await.map_err(db_error)?;
+
+    info!("{} deleted the team {}", admin.0.id, id);
+    Ok(Redirect::to(uri!(admin_route.0.clone(), teams)))
+}
+
+/// Data which needs to be given when requesting "/teams/<id>/members"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct MemberData<'r> {
+    user_id: &'r str,
+    is_admin: bool,
+}
+
+/// Adds a member to the team, or changes whether they are a team admin
+#[utoipa::path(
+    tag = "teams",
+    request_body(content = MemberData, content_type = "application/x-www-form-urlencoded"),
+    params(("id", description = "The ID of the team")),
+    responses(
+        (status = 303, description = "Redirects to the team's page"),
+        (status = 400, description = "The user ID is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not allowed to manage the team, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/teams/<id>/members", data = "<info>")]
+async fn set_member(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    user: User,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    id: i32,
+    info: CsrfForm<MemberData<'_>>,
+) -> Result<Redirect, AppError> {
+    if !can_manage(&mut db, config, &user, id).await {
+        return Err(AppError::new(
+            Status::Forbidden,
+            "You are not allowed to manage this team",
+        ));
+    }
+    if info.user_id.is_empty() {
+        return Err(AppError::new(Status::BadRequest, "A user ID is needed"));
+    }
+
+    TeamMember::set(&mut db, id, info.user_id, info.is_admin)
+        .await
+        .map_err(db_error)?;
+
+    Ok(Redirect::to(uri!(admin_route.0.clone(), team(id))))
+}
+
+/// Data which needs to be given when requesting "/teams/<id>/members/remove"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct RemoveMemberData<'r> {
+    user_id: &'r str,
+}
+
+/// Removes a member from the team
+#[utoipa::path(
+    tag = "teams",
+    request_body(content = RemoveMemberData, content_type = "application/x-www-form-urlencoded"),
+    params(("id", description = "The ID of the team")),
+    responses(
+        (status = 303, description = "Redirects to the team's page"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not allowed to manage the team, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/teams/<id>/members/remove", data = "<info>")]
+async fn remove_member(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    user: User,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    id: i32,
+    info: CsrfForm<RemoveMemberData<'_>>,
+) -> Result<Redirect, AppError> {
+    if !can_manage(&mut db, config, &user, id).await {
+        return Err(AppError::new(
+            Status::Forbidden,
+            "You are not allowed to manage this team",
+        ));
+    }
+
+    TeamMember::remove(&mut db, id, info.user_id)
+        .await
+        .map_err(db_error)?;
+
+    Ok(Redirect::to(uri!(admin_route.0.clone(), team(id))))
+}
+
+/// Data which needs to be given when requesting "/teams/<id>/prefixes"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct RuleData<'r> {
+    prefix: &'r str,
+    /// Either "prefix", "glob" or "regex"
+    kind: &'r str,
+    deny: bool,
+    max_length: Option<i32>,
+}
+
+/// Allows an administrator to add a prefix rule for the team's members
+#[utoipa::path(
+    tag = "teams",
+    request_body(content = RuleData, content_type = "application/x-www-form-urlencoded"),
+    params(("id", description = "The ID of the team")),
+    responses(
+        (status = 303, description = "Redirects to the team's page"),
+        (status = 400, description = "The rule is invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/teams/<id>/prefixes", data = "<info>")]
+async fn add_prefix(
+    _limit: RateLimit<ApiCalls>,
+    admin: Admin,
+    admin_route: &State<AdminRoute>,
+    patterns: &State<RuleCache>,
+    mut db: Connection<Db>,
+    id: i32,
+    info: CsrfForm<RuleData<'_>>,
+) -> Result<Redirect, AppError> {
+    let kind = rules::validate(patterns, info.kind, info.prefix, info.max_length).map_err(|e| {
+        warn!("Rejected rule '{}' for the team {}: {}", info.prefix, id, e);
+        AppError::new(Status::BadRequest, e)
+    })?;
+
+    Team::add_prefix(&mut db, id, info.prefix, kind, info.deny, info.max_length)
+        .await
+        .map_err(db_error)?;
+
+    info!(
+        "{} granted {} {}'{}' to the team {}",
+        admin.0.id,
+        kind.as_str(),
+        if info.deny { "deny " } else { "" },
+        info.prefix,
+        id
+    );
+    Ok(Redirect::to(uri!(admin_route.0.clone(), team(id))))
+}
+
+/// Data which needs to be given when requesting "/teams/<id>/prefixes/remove"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct PrefixData<'r> {
+    prefix: &'r str,
+}
+
+/// Allows an administrator to remove one of the team's prefix rules
+#[utoipa::path(
+    tag = "teams",
+    request_body(content = PrefixData, content_type = "application/x-www-form-urlencoded"),
+    params(("id", description = "The ID of the team")),
+    responses(
+        (status = 303, description = "Redirects to the team's page"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not an administrator, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/teams/<id>/prefixes/remove", data = "<info>")]
+async fn remove_prefix(
+    _limit: RateLimit<ApiCalls>,
+    admin: Admin,
+    admin_route: &State<AdminRoute>,
+    mut db: Connection<Db>,
+    id: i32,
+    info: CsrfForm<PrefixData<'_>>,
+) -> Result<Redirect, AppError> {
+    Team::remove_prefix(&mut db, id, info.prefix)
+        .await
+        .map_err(db_error)?;
+
+    info!(
+        "{} removed '{}' from the team {}",
+        admin.0.id, info.prefix, id
+    );
+    Ok(Redirect::to(uri!(admin_route.0.clone(), team(id))))
+}
+
+/// Adds the pages and endpoints for managing teams
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Teams Initialisation", |rocket| async {
+        let admin = AdminRoute::get(&rocket);
+
+        rocket.mount(admin, routes![teams, team]).mount(
+            API_LOCAL,
+            routes![
+                create_team,
+                delete_team,
+                set_member,
+                remove_member,
+                add_prefix,
+                remove_prefix
+            ],
+        )
+    })
+}
@@ -0,0 +1,561 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use figment::Figment;
+use ipnet::IpNet;
+use rocket::http::Status;
+use rocket::serde::json;
+use utoipa::OpenApi;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::admin::split_prefix;
+use crate::api::{self, API_LOCAL};
+use crate::auth::safe_return_to;
+use crate::cache::RedirectCache;
+use crate::canonical::canonicalize;
+use crate::config::{AppConfig, BucketConfig, NameConfig, UrlConfig};
+use crate::csrf;
+use crate::database::PrefixLink;
+use crate::error::AppError;
+use crate::hits::HitCounter;
+use crate::idempotency::is_valid_key;
+use crate::names;
+use crate::openapi::{self, ApiDoc};
+use crate::quotas::describe_window;
+use crate::ratelimit::{client_ip, Bucket};
+use crate::reserved::ReservedNames;
+use crate::rules::{self, check, Decision, RuleCache, RuleKind};
+use crate::titles::parse_title;
+use crate::webhooks::{is_public, retry_delay, sign};
+
+#[test]
+fn return_to_allows_local_paths() {
+    assert_eq!(safe_return_to("/admin"), Some("/admin".to_string()));
+    assert_eq!(
+        safe_return_to("/admin/sessions?page=2"),
+        Some("/admin/sessions?page=2".to_string())
+    );
+}
+
+#[test]
+fn return_to_rejects_other_sites() {
+    assert_eq!(safe_return_to("https://example.com/admin"), None);
+    assert_eq!(safe_return_to("//example.com/admin"), None);
+    assert_eq!(safe_return_to("/\\example.com/admin"), None);
+    assert_eq!(safe_return_to("admin"), None);
+}
+
+fn rule(prefix: &str, kind: &str, deny: bool, max_length: Option<i32>) -> PrefixLink {
+    PrefixLink {
+        user_id: "user".to_string(),
+        prefix: prefix.to_string(),
+        kind: kind.to_string(),
+        deny,
+        max_length,
+    }
+}
+
+#[test]
+fn rules_match_prefixes_globs_and_regexes() {
+    let rules = [
+        rule("docs-", "prefix", false, None),
+        rule("team-*-docs", "glob", false, None),
+        rule("v[0-9]+", "regex", false, None),
+    ];
+    let cache = RuleCache::default();
+
+    assert!(matches!(check(&cache, &rules, "docs-intro"), Decision::Allowed(_)));
+    assert!(matches!(check(&cache, &rules, "team-web-docs"), Decision::Allowed(_)));
+    assert!(matches!(check(&cache, &rules, "v12"), Decision::Allowed(_)));
+    assert!(!matches!(check(&cache, &rules, "team-web-docs2"), Decision::Allowed(_)));
+    assert!(!matches!(check(&cache, &rules, "xv12"), Decision::Allowed(_)));
+    assert_eq!(check(&cache, &rules, "other"), Decision::NoMatch);
+}
+
+#[test]
+fn deny_rules_win_and_lengths_are_limited() {
+    let rules = [
+        rule("", "prefix", false, Some(8)),
+        rule("admin*", "glob", true, None),
+    ];
+    let cache = RuleCache::default();
+
+    assert_eq!(
+        check(&cache, &rules, "administer"),
+        Decision::Denied("deny glob 'admin*'".to_string())
+    );
+    assert_eq!(
+        check(&cache, &rules, "short"),
+        Decision::Allowed("prefix ''".to_string())
+    );
+    assert_eq!(
+        check(&cache, &rules, "far-too-long"),
+        Decision::TooLong("prefix ''".to_string(), 8)
+    );
+}
+
+#[test]
+fn rule_patterns_are_compiled_once_and_checked_when_saved() {
+    let cache = RuleCache::default();
+
+    assert_eq!(
+        rules::validate(&cache, "regex", "v[0-9]+", None),
+        Ok(RuleKind::Regex)
+    );
+    let first = cache.get(RuleKind::Regex, "v[0-9]+").unwrap();
+    let second = cache.get(RuleKind::Regex, "v[0-9]+").unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+
+    assert!(rules::validate(&cache, "regex", "v[0-9", None).is_err());
+    assert!(rules::validate(&cache, "wildcard", "v*", None).is_err());
+}
+
+#[test]
+fn rules_are_narrowed_down_by_their_literal_start() {
+    assert_eq!(rules::literal_prefix(RuleKind::Prefix, "docs-"), "docs-");
+    assert_eq!(rules::literal_prefix(RuleKind::Glob, "team-*-docs"), "team-");
+    assert_eq!(rules::literal_prefix(RuleKind::Glob, "v?"), "v");
+    assert_eq!(rules::literal_prefix(RuleKind::Regex, "v[0-9]+"), "");
+}
+
+#[test]
+fn quota_windows_are_described_in_whole_units() {
+    assert_eq!(describe_window(60 * 60 * 24), "day");
+    assert_eq!(describe_window(60 * 60 * 2), "2 hours");
+    assert_eq!(describe_window(90), "90 seconds");
+}
+
+#[test]
+fn buckets_allow_a_burst_then_refill() {
+    let limit = BucketConfig {
+        burst: 2,
+        per_minute: 60,
+    };
+    let start = Instant::now();
+    let mut bucket = Bucket::new(&limit, start);
+
+    assert!(bucket.take(&limit, start).is_ok());
+    assert!(bucket.take(&limit, start).is_ok());
+    assert_eq!(bucket.take(&limit, start), Err(Duration::from_secs(1)));
+    assert!(bucket
+        .take(&limit, start + Duration::from_secs(1))
+        .is_ok());
+}
+
+#[test]
+fn client_ip_only_trusts_forwarding_from_proxies() {
+    let proxies: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+    let proxy = Some("10.0.0.1".parse().unwrap());
+    let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+    assert_eq!(
+        client_ip(proxy, Some("198.51.100.1, 203.0.113.7, 10.0.0.2"), &proxies),
+        Some(client)
+    );
+    assert_eq!(
+        client_ip(Some(client), Some("198.51.100.1"), &proxies),
+        Some(client)
+    );
+    assert_eq!(client_ip(proxy, None, &proxies), proxy);
+}
+
+#[test]
+fn redirect_cache_remembers_misses_until_invalidated() {
+    let cache = RedirectCache::new(2, Duration::from_secs(60), Duration::from_secs(60));
+
+    assert_eq!(cache.get("docs"), None);
+    cache.insert("docs", None);
+    assert_eq!(cache.get("docs"), Some(None));
+
+    cache.invalidate("docs");
+    assert_eq!(cache.get("docs"), None);
+
+    cache.insert("a", Some("https://a.example".to_string()));
+    cache.insert("b", Some("https://b.example".to_string()));
+    cache.insert("c", Some("https://c.example".to_string()));
+    assert_eq!(cache.get("a"), None);
+    assert_eq!(cache.get("c"), Some(Some("https://c.example".to_string())));
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (2, 3, 2));
+}
+
+#[test]
+fn webhook_signatures_cover_the_timestamp_and_body() {
+    assert_eq!(
+        sign("secret", 1700000000, "{}"),
+        "b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+    );
+}
+
+#[test]
+fn webhook_retries_back_off_then_give_up() {
+    assert_eq!(retry_delay(1), Some(Duration::from_secs(30)));
+    assert_eq!(retry_delay(3), Some(Duration::from_secs(120)));
+    assert_eq!(retry_delay(9), Some(Duration::from_secs(30 * 256)));
+    assert_eq!(retry_delay(10), None);
+}
+
+#[test]
+fn webhooks_are_only_sent_to_public_addresses() {
+    let public = |ip: &str| is_public(ip.parse().unwrap());
+
+    assert!(public("93.184.216.34"));
+    assert!(public("2606:2800:220:1:248:1893:25c8:1946"));
+    assert!(public("64:ff9b::5db8:d822"));
+    assert!(public("2002:5db8:d822::1"));
+
+    for ip in [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "0.1.2.3",
+        "192.0.0.8",
+        "198.18.0.1",
+        "198.19.255.255",
+        "240.0.0.1",
+        "255.255.255.255",
+        "::1",
+        "::",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+        "::127.0.0.1",
+        "::a9fe:a9fe",
+        "64:ff9b::7f00:1",
+        "64:ff9b::a9fe:a9fe",
+        "64:ff9b:1::a00:1",
+        "2002:7f00:1::",
+        "2002:a9fe:a9fe::1",
+        "2002:c0a8:101:1::1",
+    ] {
+        assert!(!public(ip), "{} should not be public", ip);
+    }
+}
+
+#[test]
+fn idempotency_keys_are_visible_ascii() {
+    assert!(is_valid_key("ci-job-1234"));
+    assert!(is_valid_key("5b0c1a3e-8f0e-4d8e-9a4b-2f6f7d1f9c11"));
+    assert!(!is_valid_key(""));
+    assert!(!is_valid_key("has space"));
+    assert!(!is_valid_key("ключ"));
+    assert!(!is_valid_key(&"a".repeat(256)));
+}
+
+#[test]
+fn openapi_spec_is_up_to_date() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+    let spec = openapi::spec();
+
+    if std::env::var_os("UPDATE_OPENAPI").is_some() {
+        std::fs::write(path, &spec).unwrap();
+    }
+
+    assert!(
+        std::fs::read_to_string(path).is_ok_and(|saved| saved == spec),
+        "openapi.json is out of date, run `UPDATE_OPENAPI=1 cargo test` to update it"
+    );
+}
+
+#[rocket::async_test]
+async fn openapi_spec_documents_every_api_route() {
+    let spec = json::to_value(ApiDoc::openapi()).unwrap();
+
+    // Local accounts mount the most routes under the API
+    let figment = Figment::from(rocket::Config::debug_default())
+        .merge(("hostname", "localhost"))
+        .merge(("auth_provider", "local"))
+        .merge(("databases.diesel_postgres.url", "postgres://localhost/links"));
+    let rocket = crate::rocket(figment).ignite().await.unwrap();
+
+    // The description and its documentation aren't part of the API
+    let undocumented = ["/openapi.json", "/docs"];
+    let routes = rocket
+        .routes()
+        .filter_map(|route| Some((route, route.uri.path().strip_prefix(API_LOCAL)?)))
+        .filter(|(_, path)| !undocumented.contains(path));
+
+    let mut checked = 0;
+    for (route, path) in routes {
+        let method = route.method.as_str().to_lowercase();
+        // Dynamic segments are written as `{name}` rather than `<name>`
+        let path = path.replace('<', "{").replace('>', "}");
+        assert!(
+            spec["paths"][&path][&method].is_object(),
+            "{} {} is not in the OpenAPI description",
+            route.method,
+            route.uri.path()
+        );
+        checked += 1;
+    }
+    assert!(checked >= api::routes().len());
+}
+
+#[test]
+fn validation_errors_are_kept_per_field() {
+    let mut errors = ValidationErrors::new();
+    errors.add("name", ValidationError::new("Forbidden name"));
+    let mut invalid_url = ValidationError::new("url");
+    invalid_url.message = Some("This isn't a valid URL".into());
+    errors.add("url", invalid_url);
+
+    let problem = json::to_value(AppError::validation(&errors).problem()).unwrap();
+    assert_eq!(problem["status"], 422);
+
+    let mut fields: Vec<_> = problem["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| {
+            (
+                e["name"].as_str().unwrap(),
+                e["description"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    fields.sort();
+    assert_eq!(
+        fields,
+        [
+            ("name", "Forbidden name"),
+            ("url", "This isn't a valid URL")
+        ]
+    );
+}
+
+#[test]
+fn problems_only_include_the_members_which_are_set() {
+    let problem = json::to_value(AppError::new(Status::Conflict, "Taken").problem()).unwrap();
+    assert_eq!(
+        problem,
+        json::json!({
+            "type": "about:blank",
+            "title": "Conflict",
+            "status": 409,
+            "detail": "Taken",
+        })
+    );
+
+    let problem = json::to_value(AppError::from(Status::Conflict).allow_force().problem()).unwrap();
+    assert_eq!(problem["allow_force"], true);
+    assert!(problem.get("detail").is_none());
+}
+
+#[test]
+fn csrf_tokens_only_match_their_session() {
+    let token = csrf::token("session-a");
+    assert_eq!(token.len(), 64);
+    assert_eq!(token, csrf::token("session-a"));
+
+    assert!(csrf::verify("session-a", &token));
+    assert!(!csrf::verify("session-b", &token));
+    assert!(!csrf::verify("session-a", &token[..32]));
+    assert!(!csrf::verify("session-a", "not hex"));
+    assert!(!csrf::verify("session-a", ""));
+}
+
+#[test]
+fn names_are_normalised_by_the_settings() {
+    let config = NameConfig::default();
+    assert_eq!(names::normalize(&config, "Docs"), "docs");
+    assert_eq!(names::normalize(&config, "ｆｕｌｌ"), "full");
+    assert_eq!(names::normalize(&config, "ﬁle"), "file");
+    // Case is folded rather than lowercased, including the letters which only
+    // appear after NFKC
+    assert_eq!(names::normalize(&config, "Straße"), "strasse");
+    assert_eq!(names::normalize(&config, "ΣΊΣΥΦΟΣ"), "σίσυφοσ");
+    assert_eq!(names::normalize(&config, "ẞ"), names::normalize(&config, "SS"));
+    assert_eq!(names::normalize(&config, "㎒"), "mhz");
+    assert_eq!(names::normalize(&config, "ᾼ"), "αι");
+
+    let config = NameConfig {
+        case_insensitive: false,
+        nfkc: false,
+        ..NameConfig::default()
+    };
+    assert_eq!(names::normalize(&config, "Docs"), "Docs");
+    assert_eq!(names::normalize(&config, "ｆｕｌｌ"), "ｆｕｌｌ");
+}
+
+#[test]
+fn look_alike_names_share_a_skeleton() {
+    let config = NameConfig::default();
+    let skeleton = |name| names::skeleton(&names::normalize(&config, name));
+
+    // The second has a Cyrillic 'а'
+    assert_eq!(skeleton("paypal"), skeleton("pаypal"));
+    assert_eq!(skeleton("Docs"), skeleton("docs"));
+    assert_ne!(skeleton("docs"), skeleton("dogs"));
+}
+
+#[test]
+fn names_have_to_fit_the_allowed_scripts() {
+    let config = NameConfig::default();
+    assert!(names::check(&config, "team-docs_2").is_ok());
+    assert!(names::check(&config, "документы").is_ok());
+    assert!(names::check(&config, "pаypal").is_err());
+    assert!(names::check(&config, "¼").is_err());
+
+    let config = NameConfig {
+        scripts: vec!["ASCII".to_string()],
+        ..NameConfig::default()
+    };
+    assert!(names::check(&config, "team-docs_2").is_ok());
+    assert!(names::check(&config, "café").is_err());
+
+    let config = NameConfig {
+        scripts: vec!["Latin".to_string(), "Greek".to_string()],
+        reject_confusables: false,
+        ..NameConfig::default()
+    };
+    assert!(names::check(&config, "café").is_ok());
+    assert!(names::check(&config, "λόγος").is_ok());
+    assert!(names::check(&config, "документы").is_err());
+}
+
+#[test]
+fn reserved_names_come_from_the_start_of_route_paths() {
+    let reserved = ReservedNames::new(
+        &NameConfig::default(),
+        ["/", "/<link>", "/login", "/api/v1/add", "/<path..>", "/go/<name>"],
+        ["css", "Status"],
+    );
+
+    for name in ["login", "api", "go", "css", "status"] {
+        assert!(reserved.contains(name), "{} should be reserved", name);
+    }
+    for name in ["", "v1", "add", "link", "<link>"] {
+        assert!(!reserved.contains(name), "{} shouldn't be reserved", name);
+    }
+}
+
+#[test]
+fn urls_to_the_same_page_have_the_same_canonical_form() {
+    let config = UrlConfig::default();
+    let canonical = canonicalize(&config, "https://x.com/a");
+
+    for url in [
+        "https://x.com/a",
+        "HTTPS://X.com/a/",
+        "https://x.com:443/a",
+        "https://x.com/a?utm_source=mail&utm_campaign=launch",
+        " https://x.com/a?fbclid=abc ",
+    ] {
+        assert_eq!(canonicalize(&config, url), canonical, "{}", url);
+    }
+
+    assert_eq!(
+        canonicalize(&config, "https://x.com/a?b=2&a=1&utm_medium=x"),
+        canonicalize(&config, "https://x.com/a/?a=1&b=2")
+    );
+    assert_eq!(canonicalize(&config, "https://x.com/"), "https://x.com/");
+    assert_ne!(canonicalize(&config, "https://x.com/A"), canonical);
+    assert_ne!(canonicalize(&config, "https://x.com:8443/a"), canonical);
+    assert_ne!(canonicalize(&config, "http://x.com/a"), canonical);
+}
+
+#[test]
+fn only_the_configured_parameters_are_stripped() {
+    let config = UrlConfig {
+        strip_params: vec!["ref".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        canonicalize(&config, "https://x.com/?ref=feed&utm_source=mail"),
+        "https://x.com/?utm_source=mail"
+    );
+    assert_eq!(canonicalize(&config, "not a url"), "not a url");
+}
+
+#[test]
+fn titles_are_read_from_the_page() {
+    assert_eq!(
+        parse_title("<html><head><TITLE lang=\"en\">\n  Docs &amp; Guides\n</TITLE></head>"),
+        Some("Docs & Guides".to_string())
+    );
+    assert_eq!(
+        parse_title("<title>Caf\u{e9} &#8211; Menu</title>"),
+        Some("Caf\u{e9} \u{2013} Menu".to_string())
+    );
+    assert_eq!(parse_title("<title>  </title>"), None);
+    assert_eq!(parse_title("<title>No end"), None);
+    assert_eq!(parse_title("<h1>Heading</h1>"), None);
+}
+
+#[test]
+fn tags_are_stored_in_lower_case_once() {
+    assert_eq!(
+        api::normalize_tags(&["Docs", " team ", "docs", ""]),
+        vec!["docs".to_string(), "team".to_string()]
+    );
+}
+
+#[test]
+fn new_names_are_split_at_the_longest_prefix() {
+    let prefixes = ["", "a-", "a-team-"];
+    assert_eq!(
+        split_prefix(&prefixes, "a-team-docs"),
+        (Some("a-team-"), "docs")
+    );
+    assert_eq!(split_prefix(&prefixes, "a-docs"), (Some("a-"), "docs"));
+    assert_eq!(split_prefix(&prefixes, "docs"), (Some(""), "docs"));
+    assert_eq!(split_prefix(&["a-"], "docs"), (None, "docs"));
+}
+
+#[test]
+fn providers_cannot_take_the_default_name() {
+    let provider = |name: &str| {
+        json::json!({
+            "name": name,
+            "client_id": "id",
+            "client_secret": "secret",
+            "client_url": "https://auth.example.com",
+        })
+    };
+    let config = |providers| {
+        json::from_value::<AppConfig>(json::json!({
+            "hostname": "https://example.com/",
+            "providers": providers,
+        }))
+    };
+
+    assert!(config(vec![provider("work")]).is_ok());
+    assert!(config(vec![provider("work"), provider("default")]).is_err());
+}
+
+#[test]
+fn suggestions_do_not_depend_on_the_directory() {
+    let config = |settings: json::Value| {
+        let mut value = json::json!({ "hostname": "https://example.com/" });
+        value.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+        json::from_value::<AppConfig>(value).unwrap()
+    };
+
+    let defaults = config(json::json!({}));
+    assert!(defaults.suggestions);
+    assert!(!defaults.directory);
+
+    let off = config(json::json!({ "suggestions": false, "directory": true }));
+    assert!(!off.suggestions);
+    assert!(off.directory);
+}
+
+#[test]
+fn hits_are_counted_per_name_until_taken() {
+    let counter = HitCounter::default();
+    counter.record("docs");
+    counter.record("docs");
+    counter.record("documentation");
+
+    let counts = counter.take();
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts["docs"], 2);
+    assert_eq!(counts["documentation"], 1);
+    assert!(counter.take().is_empty());
+}
@@ -0,0 +1,174 @@
+//! Fills in the titles of links which were created without one, using the
+//! `<title>` of the page they redirect to. Pages are fetched in the
+//! background after the link is saved, so creating links isn't slowed down,
+//! and this is turned off unless `urls.fetch_titles` is set as it makes
+//! requests to any URL users give. Like webhooks, pages are only fetched from
+//! public addresses, and each redirect is checked before it is followed.
+
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::mpsc};
+use rocket_db_pools::diesel::PgPool;
+use rocket_db_pools::Database;
+
+use crate::config::AppConfig;
+use crate::database::{Db, Url};
+use crate::webhooks::resolve_public;
+
+/// How long to wait for the page
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How much of the page is read looking for the title
+const MAX_PAGE_SIZE: usize = 64 * 1024;
+/// The longest title which is kept, longer ones are cut short
+const MAX_TITLE_LENGTH: usize = 200;
+/// How many redirects are followed to get to the page
+const MAX_REDIRECTS: usize = 5;
+/// How many links can be waiting for their titles, any more are skipped
+const QUEUE_SIZE: usize = 100;
+
+/// Queues links to have their titles fetched, this does nothing when fetching
+/// titles is turned off
+pub struct TitleFetcher(Option<mpsc::Sender<(String, String)>>);
+
+impl TitleFetcher {
+    /// Fetches the title of the page a link redirects to in the background,
+    /// which is used if the link still doesn't have a title by then. The
+    /// link is skipped if too many are already waiting.
+    pub fn fetch(&self, name: &str, url: &str) {
+        if let Some(sender) = &self.0 {
+            if let Err(mpsc::error::TrySendError::Full(_)) =
+                sender.try_send((name.to_string(), url.to_string()))
+            {
+                warn!("Too many links are waiting for titles, skipping {}", name);
+            }
+        }
+    }
+}
+
+/// Returns the text of the first `<title>` element in an HTML page, with
+/// entities decoded and whitespace collapsed
+pub fn parse_title(html: &str) -> Option<String> {
+    // Lower casing only ASCII keeps the byte offsets the same
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+
+    let title = html_escape::decode_html_entities(&html[start..end])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    match title.is_empty() {
+        true => None,
+        false => Some(title.chars().take(MAX_TITLE_LENGTH).collect()),
+    }
+}
+
+/// Makes a request for a page, only to public addresses. The host is checked
+/// and the request made to the addresses which were checked, so it can't be
+/// looked up again to somewhere else.
+async fn get(url: &reqwest::Url) -> anyhow::Result<reqwest::Response> {
+    if url.scheme() != "https" && url.scheme() != "http" {
+        anyhow::bail!("Only http:// and https:// pages are fetched");
+    }
+    let addrs = resolve_public(url).await.map_err(anyhow::Error::msg)?;
+
+    let mut client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(domain) = url.domain() {
+        client = client.resolve_to_addrs(domain, &addrs);
+    }
+
+    Ok(client.build()?.get(url.clone()).send().await?)
+}
+
+/// Fetches the start of an HTML page and returns its title. Redirects are
+/// followed here rather than by the client, so each one is checked.
+async fn fetch_title(url: &str) -> anyhow::Result<Option<String>> {
+    let mut url = reqwest::Url::parse(url)?;
+    let mut redirects = 0;
+    let mut response = loop {
+        let response = get(&url).await?;
+        if !response.status().is_redirection() {
+            break response.error_for_status()?;
+        }
+
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .ok_or_else(|| anyhow::anyhow!("Redirected without a location"))?;
+        if redirects == MAX_REDIRECTS {
+            anyhow::bail!("Too many redirects");
+        }
+        redirects += 1;
+        url = url.join(location.to_str()?)?;
+    };
+
+    let is_html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    if !is_html {
+        return Ok(None);
+    }
+
+    let mut page = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        page.extend_from_slice(&chunk);
+        if page.len() >= MAX_PAGE_SIZE {
+            break;
+        }
+    }
+
+    Ok(parse_title(&String::from_utf8_lossy(&page)))
+}
+
+/// Fetches the titles of the links which are queued, one at a time
+async fn run(pool: PgPool, mut queue: mpsc::Receiver<(String, String)>) {
+    while let Some((name, url)) = queue.recv().await {
+        let title = match fetch_title(&url).await {
+            Ok(Some(title)) => title,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!("Could not fetch the title of {}: {}", url, e);
+                continue;
+            }
+        };
+
+        let res = match pool.get().await {
+            Ok(mut conn) => Url::set_default_title(&mut conn, &name, &url, &title)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            warn!("Could not save the title of {}: {}", name, e);
+        }
+    }
+}
+
+/// Starts fetching titles for new links, if it is turned on
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Link Titles", |rocket| async {
+        let enabled = rocket
+            .state::<AppConfig>()
+            .is_some_and(|c| c.urls.fetch_titles);
+        if !enabled {
+            return rocket.manage(TitleFetcher(None));
+        }
+
+        let (sender, queue) = mpsc::channel(QUEUE_SIZE);
+        rocket
+            .manage(TitleFetcher(Some(sender)))
+            .attach(AdHoc::on_liftoff("Link Title Fetcher", |rocket| {
+                Box::pin(async move {
+                    if let Some(db) = Db::fetch(rocket) {
+                        tokio::spawn(run((**db).clone(), queue));
+                    }
+                })
+            }))
+    })
+}
@@ -0,0 +1,530 @@
+//! Webhooks which tell other systems when links change. Events are added to
+//! an outbox table in the same transaction as the change, and a background
+//! worker sends them, signed with the webhook's secret, retrying with backoff
+//! until they are accepted.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::{json, Serialize};
+use rocket::tokio;
+use rocket::State;
+use rocket_db_pools::diesel::{AsyncPgConnection, PgPool};
+use rocket_db_pools::{Connection, Database};
+use rocket_dyn_templates::{context, Template};
+use sha2::Sha256;
+use utoipa::ToSchema;
+
+use crate::admin::AdminRoute;
+use crate::api::API_LOCAL;
+use crate::auth::User;
+use crate::config::AppConfig;
+use crate::csrf::{self, CsrfForm, NoFields};
+use crate::database::{Db, TeamMember, Url, Webhook, WebhookDelivery};
+use crate::error::AppError;
+use crate::ratelimit::{ApiCalls, RateLimit};
+use crate::utils::random_colour;
+
+/// How often the outbox is checked for deliveries which are due
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How many deliveries are sent at a time
+const BATCH_SIZE: i64 = 20;
+/// How long a delivery is held by one instance while it is being sent
+const DELIVERY_LEASE: Duration = Duration::from_secs(60);
+/// How long to wait for the receiver to respond
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many times a delivery is attempted before giving up
+const MAX_ATTEMPTS: i32 = 10;
+/// How long to wait before the first retry, this is doubled after each
+/// failed attempt
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// The longest amount of time to wait between attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60 * 6);
+/// How many deliveries are shown in the log
+const LOG_SIZE: i64 = 50;
+
+/// Something which happened to a link
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkEvent {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl LinkEvent {
+    pub const ALL: [LinkEvent; 3] = [LinkEvent::Created, LinkEvent::Updated, LinkEvent::Deleted];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkEvent::Created => "link.created",
+            LinkEvent::Updated => "link.updated",
+            LinkEvent::Deleted => "link.deleted",
+        }
+    }
+}
+
+/// The body sent to webhooks
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Payload<'a> {
+    event: &'a str,
+    occurred_at: String,
+    /// The user who made the change
+    actor: &'a str,
+    link: &'a Url,
+}
+
+/// Adds the event to the outbox of every webhook which wants it. This should
+/// be called in the same transaction as the change, so that the events are
+/// only sent if the change is made.
+pub async fn raise(
+    conn: &mut Connection<Db>,
+    event: LinkEvent,
+    link: &Url,
+    actor: &str,
+) -> Result<(), diesel::result::Error> {
+    let webhooks = Webhook::get_all(conn).await;
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let payload = json::to_string(&Payload {
+        event: event.as_str(),
+        occurred_at: Utc::now().to_rfc3339(),
+        actor,
+        link,
+    })
+    .expect("Could not serialise webhook payload");
+
+    for webhook in webhooks {
+        let wanted =
+            webhook.events.is_empty() || webhook.events.iter().any(|e| e == event.as_str());
+        if !wanted {
+            continue;
+        }
+
+        let owns_link = match (&link.team_id, &link.user_id) {
+            (Some(team_id), _) => TeamMember::get(conn, *team_id, &webhook.user_id)
+                .await
+                .is_some(),
+            (None, Some(user_id)) => *user_id == webhook.user_id,
+            (None, None) => false,
+        };
+        if webhook.all_links || owns_link {
+            WebhookDelivery::enqueue(conn, webhook.id, event.as_str(), &payload).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Signs a delivery so the receiver can check it came from us, as the hex
+/// encoded HMAC-SHA256 of the timestamp and body joined by a dot
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Returns how long to wait before the next attempt, after `attempts` have
+/// failed, or `None` if we should give up
+pub fn retry_delay(attempts: i32) -> Option<Duration> {
+    if attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1).max(0) as u32);
+    Some(MIN_RETRY_DELAY.saturating_mul(factor).min(MAX_RETRY_DELAY))
+}
+
+/// Returns whether webhooks can be sent to an address, which they can't be
+/// for this machine, private networks or the other ranges which are reserved
+/// rather than on the internet, so that they can't be used to reach services
+/// which aren't meant to be exposed
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                // "This network", including 0.0.0.0
+                || a == 0
+                // Shared address space, used for carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64)
+                // IETF protocol assignments
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking
+                || (a == 198 && (b & 0xfe) == 18)
+                // Reserved for future use, including the broadcast address
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Returns the IPv4 address an IPv6 address stands for, when it is one of
+/// the forms which are turned into IPv4 on the way: IPv4-mapped, the
+/// deprecated IPv4-compatible, NAT64 or 6to4
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let ipv4 = |s: &[u16]| Ipv4Addr::from((u32::from(s[0]) << 16) | u32::from(s[1]));
+
+    match segments {
+        [0, 0, 0, 0, 0, 0xffff, ..] | [0, 0, 0, 0, 0, 0, ..] => Some(ipv4(&segments[6..])),
+        // The well-known NAT64 prefix, and the one for local use
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] | [0x64, 0xff9b, 1, ..] => Some(ipv4(&segments[6..])),
+        [0x2002, ..] => Some(ipv4(&segments[1..3])),
+        _ => None,
+    }
+}
+
+/// Looks up the addresses a URL points to, failing with a message for the
+/// user unless there are some and they are all public. Requests should then
+/// be made to these addresses, rather than looking the host up again.
+pub async fn resolve_public(url: &reqwest::Url) -> Result<Vec<SocketAddr>, String> {
+    let port = url.port_or_known_default().unwrap_or(443);
+    let host = url
+        .host_str()
+        .ok_or_else(|| "The URL needs a host".to_string())?;
+
+    // IPv6 addresses are kept in brackets in URLs
+    let addrs: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| format!("The host '{}' could not be found", host))?
+            .collect(),
+    };
+
+    if addrs.is_empty() {
+        return Err("The URL's host has no addresses".to_string());
+    }
+    if addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err("The URL can't point to a private or local address".to_string());
+    }
+
+    Ok(addrs)
+}
+
+/// Sends a delivery, returning the status the receiver responded with. The
+/// host is looked up and checked again first, and the request is made to the
+/// addresses which were checked, so it can't be pointed somewhere private
+/// after the webhook was registered.
+async fn send(webhook: &Webhook, delivery: &WebhookDelivery) -> anyhow::Result<u16> {
+    let url = reqwest::Url::parse(&webhook.url)?;
+    let addrs = resolve_public(&url).await.map_err(anyhow::Error::msg)?;
+
+    let mut client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(domain) = url.domain() {
+        client = client.resolve_to_addrs(domain, &addrs);
+    }
+
+    let timestamp = Utc::now().timestamp();
+    let signature = sign(&webhook.secret, timestamp, &delivery.payload);
+
+    let response = client
+        .build()?
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Event", &delivery.event)
+        .header("X-Webhook-Delivery", delivery.id.to_string())
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header("X-Webhook-Signature", format!("sha256={}", signature))
+        .body(delivery.payload.clone())
+        .send()
+        .await?;
+
+    Ok(response.status().as_u16())
+}
+
+/// Sends the deliveries which are due, returning how many there were
+async fn deliver_due(pool: &PgPool) -> anyhow::Result<usize> {
+    let mut conn = pool.get().await?;
+    let lease = chrono::Duration::from_std(DELIVERY_LEASE)?;
+    let due = WebhookDelivery::claim_due(&mut conn, BATCH_SIZE, lease).await?;
+
+    for delivery in &due {
+        let Some(webhook) = Webhook::get(&mut conn, delivery.webhook_id).await else {
+            continue;
+        };
+
+        let (status, error) = match send(&webhook, delivery).await {
+            Ok(status) if (200..300).contains(&status) => (Some(status), None),
+            Ok(status) => (Some(status), Some(format!("Responded with {}", status))),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let next_attempt_at = match error {
+            Some(_) => retry_delay(delivery.attempts + 1)
+                .and_then(|d| chrono::Duration::from_std(d).ok())
+                .map(|d| Utc::now().naive_utc() + d),
+            None => None,
+        };
+        if let Some(e) = &error {
+            warn!(
+                "Could not deliver {} to webhook {}: {}",
+                delivery.id, webhook.id, e
+            );
+        }
+
+        WebhookDelivery::record_attempt(
+            &mut conn,
+            delivery.id,
+            error.is_none(),
+            status.map(i32::from),
+            error.as_deref(),
+            next_attempt_at,
+        )
+        .await?;
+    }
+
+    Ok(due.len())
+}
+
+/// Keeps sending deliveries from the outbox as they become due
+async fn run(pool: PgPool) {
+    loop {
+        match deliver_due(&pool).await {
+            // There may be more waiting
+            Ok(sent) if sent as i64 == BATCH_SIZE => continue,
+            Ok(_) => {}
+            Err(e) => warn!("Could not send webhooks: {}", e),
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Returns the webhook if the user is allowed to see it, which they are if
+/// they registered it or are an administrator
+async fn get_webhook(
+    conn: &mut AsyncPgConnection,
+    config: &AppConfig,
+    user: &User,
+    id: i32,
+) -> Result<Webhook, AppError> {
+    match Webhook::get(conn, id).await {
+        Some(w) if w.user_id == user.id || config.is_admin(&user.id) => Ok(w),
+        _ => Err(AppError::new(
+            Status::NotFound,
+            "This webhook does not exist",
+        )),
+    }
+}
+
+/// Turns a database error into one which can be returned to the user
+fn db_error(e: diesel::result::Error) -> AppError {
+    error!("Could not update webhook: {}", e);
+    AppError::internal("Could not update the webhook")
+}
+
+/// Lists the user's webhooks, or every webhook for administrators
+#[get("/webhooks")]
+async fn webhooks(config: &State<AppConfig>, user: User, mut db: Connection<Db>) -> Template {
+    let is_admin = config.is_admin(&user.id);
+    let webhooks = if is_admin {
+        Webhook::get_all(&mut db).await
+    } else {
+        Webhook::get_for_user(&mut db, &user.id).await
+    };
+
+    Template::render(
+        "webhooks",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            webhooks: webhooks,
+            events: LinkEvent::ALL.map(|e| e.as_str()),
+            is_admin: is_admin,
+            csrf_token: csrf::token(&user.session),
+            name: "Webhooks",
+        },
+    )
+}
+
+/// Shows a webhook's secret and its recent deliveries
+#[get("/webhooks/<id>")]
+async fn webhook(
+    config: &State<AppConfig>,
+    user: User,
+    mut db: Connection<Db>,
+    id: i32,
+) -> Result<Template, AppError> {
+    let webhook = get_webhook(&mut db, config, &user, id).await?;
+    let deliveries: Vec<_> = WebhookDelivery::get_recent(&mut db, id, LOG_SIZE)
+        .await
+        .into_iter()
+        .map(|d| {
+            let state = match (d.delivered_at, d.failed) {
+                (Some(_), _) => "Delivered",
+                (None, true) => "Failed",
+                (None, false) => "Pending",
+            };
+
+            context! {
+                id: d.id,
+                event: d.event,
+                created_at: d.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                state: state,
+                attempts: d.attempts,
+                last_status: d.last_status,
+                last_error: d.last_error,
+            }
+        })
+        .collect();
+
+    Ok(Template::render(
+        "webhook",
+        context! {
+            api: API_LOCAL,
+            colour: random_colour(),
+            webhook: webhook,
+            deliveries: deliveries,
+            csrf_token: csrf::token(&user.session),
+            name: "Webhook",
+        },
+    ))
+}
+
+/// Data which needs to be given when requesting "/webhooks"
+#[derive(Debug, FromForm, ToSchema)]
+pub struct WebhookData<'r> {
+    url: &'r str,
+    /// The events to send, or all of them if none are picked
+    events: Vec<&'r str>,
+    all_links: bool,
+}
+
+/// Registers a new webhook for the user
+#[utoipa::path(
+    tag = "webhooks",
+    request_body(content = WebhookData, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the new webhook's page"),
+        (status = 400, description = "The URL or events are invalid, or the URL is a private or local address", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Only administrators can send events for every link, or the CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/webhooks", data = "<info>")]
+async fn create_webhook(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    admin_route: &State<AdminRoute>,
+    user: User,
+    mut db: Connection<Db>,
+    info: CsrfForm<WebhookData<'_>>,
+) -> Result<Redirect, AppError> {
+    let url = reqwest::Url::parse(info.url)
+        .ok()
+        .filter(|url| url.scheme() == "https" || url.scheme() == "http")
+        .ok_or_else(|| {
+            AppError::new(
+                Status::BadRequest,
+                "The URL needs to start with http:// or https://",
+            )
+        })?;
+    resolve_public(&url)
+        .await
+        .map_err(|e| AppError::new(Status::BadRequest, e))?;
+
+    let valid_events = info
+        .events
+        .iter()
+        .all(|e| LinkEvent::ALL.iter().any(|x| x.as_str() == *e));
+    if !valid_events {
+        return Err(AppError::new(Status::BadRequest, "Unknown event"));
+    }
+
+    // Only administrators can see events for links which aren't theirs
+    if info.all_links && !config.is_admin(&user.id) {
+        return Err(AppError::new(
+            Status::Forbidden,
+            "Only administrators can send events for every link",
+        ));
+    }
+
+    let events: Vec<String> = info.events.iter().map(|e| e.to_string()).collect();
+    let id = Webhook::create(&mut db, &user.id, info.url, &events, info.all_links)
+        .await
+        .map_err(db_error)?;
+
+    info!("{} registered the webhook {} for {}", user.id, id, info.url);
+    Ok(Redirect::to(uri!(admin_route.0.clone(), webhook(id))))
+}
+
+/// Removes a webhook, along with its delivery log
+#[utoipa::path(
+    tag = "webhooks",
+    request_body(content = NoFields, content_type = "application/x-www-form-urlencoded"),
+    params(("id", description = "The ID of the webhook")),
+    responses(
+        (status = 303, description = "Redirects to the webhooks page"),
+        (status = 401, description = "Not logged in", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The CSRF token is missing or wrong", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "The webhook doesn't exist, or belongs to someone else", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many requests", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("session" = [])),
+)]
+#[post("/webhooks/<id>/delete", data = "<_csrf>")]
+async fn delete_webhook(
+    _limit: RateLimit<ApiCalls>,
+    config: &State<AppConfig>,
+    admin_route: &State<AdminRoute>,
+    user: User,
+    mut db: Connection<Db>,
+    id: i32,
+    _csrf: CsrfForm<NoFields>,
+) -> Result<Redirect, AppError> {
+    get_webhook(&mut db, config, &user, id).await?;
+    Webhook::delete(&mut db, id).await.map_err(db_error)?;
+
+    info!("{} deleted the webhook {}", user.id, id);
+    Ok(Redirect::to(uri!(admin_route.0.clone(), webhooks)))
+}
+
+/// Adds the pages and endpoints for managing webhooks, and starts sending
+/// deliveries once the application has launched
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Webhooks Initialisation", |rocket| async {
+        let admin = AdminRoute::get(&rocket);
+
+        rocket
+            .mount(admin, routes![webhooks, webhook])
+            .mount(API_LOCAL, routes![create_webhook, delete_webhook])
+            .attach(AdHoc::on_liftoff("Webhook Delivery", |rocket| {
+                Box::pin(async move {
+                    match Db::fetch(rocket) {
+                        Some(db) => {
+                            tokio::spawn(run((**db).clone()));
+                        }
+                        None => error!("Could not start sending webhooks without a database"),
+                    }
+                })
+            }))
+    })
+}
@@ -1,19 +1,28 @@
-async function send_req(form, data, callback) {
+async function send_req(form, data, callback, on_problem) {
+  // The token goes in a header, as it isn't part of the request
+  const csrf = form.querySelector('[name="csrf_token"]');
+
   return fetch(form.action, {
     method: "POST",
     body: JSON.stringify(data),
     headers: {
-      "Content-type": "application/json; charset=UTF-8"
+      "Content-type": "application/json; charset=UTF-8",
+      "Accept": "application/json",
+      "X-CSRF-Token": csrf ? csrf.value : "",
     },
   })
-    .then((response) => {
-      if (!response.ok || response.status.toString()[0] != "2") {
-        throw Error("Status: " + response.status);
+    .then(async (response) => {
+      // Some successful responses don't have a body
+      const text = await response.text();
+      const json = text ? JSON.parse(text) : {};
+
+      if (response.ok) {
+        callback(json, form, data);
+      } else if (on_problem && json.title) {
+        on_problem(json, form, data);
+      } else {
+        throw Error(json.detail || json.title || "Status: " + response.status);
       }
-      return response.json();
-    })
-    .then((json) => {
-      callback(json, form, data)
     })
     .catch((err) => {
       console.error("Could not submit form: " + err);
@@ -69,7 +78,7 @@ function reset_form(form) {
   err_div.hidden = true;
 }
 
-function init_form(orig_form, callback, validate) {
+function init_form(orig_form, callback, validate, on_problem) {
   const handleSubmit = (event) => {
     event.preventDefault();
 
@@ -83,7 +92,7 @@ function init_form(orig_form, callback, validate) {
     }
     if (!data) return;
 
-    send_req(form, data, callback)
+    send_req(form, data, callback, on_problem)
       .finally(() => btn.disabled = false);
   }
 
@@ -91,7 +100,9 @@ function init_form(orig_form, callback, validate) {
 }
 
 function get_form_obj(form) {
-  return Object.fromEntries(new FormData(form));
+  const data = Object.fromEntries(new FormData(form));
+  delete data.csrf_token;
+  return data;
 }
 
 function copy_text_in(id) {